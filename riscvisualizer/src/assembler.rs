@@ -1,5 +1,1286 @@
-use isa;
+//! A two-pass RV32I assembler. The first pass lays out every statement
+//! and records where labels land; the second resolves label references
+//! and encodes instructions. `.text` is placed at address 0 and `.data`
+//! right after it.
 
-fn assemble(input: &str) -> Vec<isa::Instruction> {
+use std::collections::HashMap;
+use std::fmt;
 
+use binary;
+use isa::{self, Instruction, Register};
+
+/// A stretch of one source line. Lines and columns start at 1, and
+/// columns count characters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub length: usize,
+}
+
+impl Span {
+    fn new(line: usize, column: usize, length: usize) -> Span {
+        Span {
+            line: line,
+            column: column,
+            length: length,
+        }
+    }
+
+    /// The span from the start of this one to the end of `other`.
+    fn to(self, other: Span) -> Span {
+        Span::new(self.line, self.column, other.column + other.length - self.column)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Error {
+    pub span: Span,
+    pub message: String,
+}
+
+impl Error {
+    fn new<S: Into<String>>(span: Span, message: S) -> Error {
+        Error {
+            span: span,
+            message: message.into(),
+        }
+    }
+
+    /// Formats the error like a compiler would: the file, line and
+    /// column, then the offending line with the span underlined.
+    pub fn render(&self, filename: &str, source: &str) -> String {
+        let line = source.lines().nth(self.span.line - 1).unwrap_or("");
+        // Copy tabs so the caret lines up however they're displayed
+        let indent: String = line.chars().take(self.span.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let underline: String = ::std::iter::repeat('^').take(::std::cmp::max(1, self.span.length)).collect();
+
+        format!("{}:{}:{}: error: {}\n{}\n{}{}",
+                filename, self.span.line, self.span.column, self.message, line, indent, underline)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.span.line, self.span.column, self.message)
+    }
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// An assembled program, ready to copy into memory at address 0.
+pub struct Image {
+    pub words: Vec<u32>,
+    // in bytes
+    pub text_size: u32,
+    pub data_start: u32,
+    pub labels: HashMap<String, u32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TokenKind {
+    Ident(String),
+    Integer(i64),
+    Str(String),
+    Comma,
+    Colon,
+    LParen,
+    RParen,
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    kind: TokenKind,
+    span: Span,
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_' || c == '.'
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.' || c == '$'
+}
+
+fn parse_integer(text: &str) -> Option<i64> {
+    let (negative, digits) = if text.starts_with('-') { (true, &text[1..]) } else { (false, text) };
+    let value = if digits.starts_with("0x") || digits.starts_with("0X") {
+        i64::from_str_radix(&digits[2..], 16)
+    }
+    else if digits.starts_with("0b") || digits.starts_with("0B") {
+        i64::from_str_radix(&digits[2..], 2)
+    }
+    else {
+        digits.parse()
+    };
+    value.ok().map(|value| if negative { -value } else { value })
+}
+
+fn unescape(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        '0' => Some('\0'),
+        '\\' | '"' | '\'' => Some(c),
+        _ => None,
+    }
+}
+
+fn tokenize(line: &str, number: usize) -> Result<Vec<Token>> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        let single = match c {
+            ',' => Some(TokenKind::Comma),
+            ':' => Some(TokenKind::Colon),
+            '(' => Some(TokenKind::LParen),
+            ')' => Some(TokenKind::RParen),
+            _ => None,
+        };
+
+        let kind = if c == '#' {
+            break;
+        }
+        else if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        else if let Some(kind) = single {
+            i += 1;
+            kind
+        }
+        else if is_ident_start(c) {
+            while i < chars.len() && is_ident(chars[i]) {
+                i += 1;
+            }
+            TokenKind::Ident(chars[start..i].iter().cloned().collect())
+        }
+        else if c.is_digit(10) || c == '-' {
+            i += 1;
+            while i < chars.len() && chars[i].is_alphanumeric() {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().cloned().collect();
+            match parse_integer(&text) {
+                Some(value) => TokenKind::Integer(value),
+                None => return Err(Error::new(Span::new(number, start + 1, i - start),
+                                              format!("invalid number {}", text))),
+            }
+        }
+        else if c == '\'' {
+            // Character literal, like 'a' or '\n'
+            let (value, length) = match (chars.get(i + 1), chars.get(i + 2), chars.get(i + 3)) {
+                (Some(&'\\'), Some(&e), Some(&'\'')) => (unescape(e), 4),
+                (Some(&c), Some(&'\''), _) if c != '\\' => (Some(c), 3),
+                _ => (None, 1),
+            };
+            match value {
+                Some(value) => {
+                    i += length;
+                    TokenKind::Integer(value as i64)
+                }
+                None => return Err(Error::new(Span::new(number, start + 1, length), "invalid character literal")),
+            }
+        }
+        else if c == '"' {
+            let mut text = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err(Error::new(Span::new(number, start + 1, i - start), "unterminated string")),
+                    Some(&'"') => break,
+                    Some(&'\\') => {
+                        match chars.get(i + 1).and_then(|&e| unescape(e)) {
+                            Some(e) => text.push(e),
+                            None => return Err(Error::new(Span::new(number, i + 1, 2), "invalid escape sequence")),
+                        }
+                        i += 2;
+                    }
+                    Some(&c) => {
+                        text.push(c);
+                        i += 1;
+                    }
+                }
+            }
+            i += 1;
+            TokenKind::Str(text)
+        }
+        else {
+            return Err(Error::new(Span::new(number, start + 1, 1), format!("unexpected character {}", c)));
+        };
+
+        tokens.push(Token {
+            kind: kind,
+            span: Span::new(number, start + 1, i - start),
+        });
+    }
+
+    Ok(tokens)
+}
+
+/// Register names, like mnemonics, can be written in any case.
+fn parse_register(name: &str) -> Option<Register> {
+    Register::from_name(&name.to_lowercase()).ok()
+}
+
+#[derive(Clone, Debug)]
+enum OperandKind {
+    Register(Register),
+    Immediate(i64),
+    Label(String),
+    Str(String),
+    // offset(base)
+    Memory(i64, Register),
+}
+
+#[derive(Clone, Debug)]
+struct Operand {
+    kind: OperandKind,
+    span: Span,
+}
+
+fn parse_operand(tokens: &[Token]) -> Result<Operand> {
+    let span = tokens[0].span.to(tokens[tokens.len() - 1].span);
+    let invalid = || Error::new(span, "invalid operand");
+
+    let kind = if tokens.len() == 1 {
+        match tokens[0].kind {
+            TokenKind::Ident(ref name) => match parse_register(name) {
+                Some(register) => OperandKind::Register(register),
+                None => OperandKind::Label(name.clone()),
+            },
+            TokenKind::Integer(value) => OperandKind::Immediate(value),
+            TokenKind::Str(ref text) => OperandKind::Str(text.clone()),
+            _ => return Err(invalid()),
+        }
+    }
+    else {
+        // offset(base), where the offset can be left out
+        let (offset, rest) = match tokens[0].kind {
+            TokenKind::Integer(offset) => (offset, &tokens[1..]),
+            _ => (0, tokens),
+        };
+        if rest.len() != 3 || rest[0].kind != TokenKind::LParen || rest[2].kind != TokenKind::RParen {
+            return Err(invalid());
+        }
+        match rest[1].kind {
+            TokenKind::Ident(ref base) => match parse_register(base) {
+                Some(register) => OperandKind::Memory(offset, register),
+                None => return Err(Error::new(rest[1].span, format!("{} is not a register", base))),
+            },
+            _ => return Err(invalid()),
+        }
+    };
+
+    Ok(Operand {
+        kind: kind,
+        span: span,
+    })
+}
+
+/// Splits the tokens after a mnemonic on commas and parses each piece.
+fn parse_operands(tokens: &[Token], end: Span) -> Result<Vec<Operand>> {
+    let mut operands = vec![];
+    if tokens.is_empty() {
+        return Ok(operands);
+    }
+
+    let mut start = 0;
+    for i in 0..tokens.len() + 1 {
+        if i < tokens.len() && tokens[i].kind != TokenKind::Comma {
+            continue;
+        }
+        if start == i {
+            // Point at the stray comma, or the end of the line
+            let span = tokens.get(i).map_or(end, |token| token.span);
+            return Err(Error::new(span, "missing operand"));
+        }
+        operands.push(try!(parse_operand(&tokens[start..i])));
+        start = i + 1;
+    }
+    Ok(operands)
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Mnemonic {
+    R(isa::ROpcode),
+    RShift(isa::RShiftOpcode),
+    // arithmetic with an immediate: rd, rs1, imm
+    I(isa::IOpcode),
+    // rd, offset(rs1)
+    Load(isa::IOpcode),
+    Jalr,
+    Scall,
+    S(isa::SOpcode),
+    SB(isa::SBOpcode),
+    U(isa::UOpcode),
+    Jal,
+    Pseudo(Pseudo),
+}
+
+/// Pseudo-instructions, which expand to one or more real instructions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Pseudo {
+    Nop,
+    Li,
+    La,
+    Mv,
+    Not,
+    Neg,
+    J,
+    Jr,
+    Ret,
+    Call,
+    Tail,
+    Beqz,
+    Bnez,
+    Bgt,
+    Ble,
+    Seqz,
+    Snez,
+}
+
+fn mnemonic(name: &str) -> Option<Mnemonic> {
+    use isa::ROpcode::*;
+    use isa::RShiftOpcode::*;
+    use isa::IOpcode::*;
+    use isa::SOpcode::*;
+    use isa::SBOpcode::*;
+    use isa::UOpcode::*;
+
+    Some(match name {
+        "add" => Mnemonic::R(ADD),
+        "sub" => Mnemonic::R(SUB),
+        "sll" => Mnemonic::R(SLL),
+        "slt" => Mnemonic::R(SLT),
+        "sltu" => Mnemonic::R(SLTU),
+        "xor" => Mnemonic::R(XOR),
+        "srl" => Mnemonic::R(SRL),
+        "sra" => Mnemonic::R(SRA),
+        "or" => Mnemonic::R(OR),
+        "and" => Mnemonic::R(AND),
+        "slli" => Mnemonic::RShift(SLLI),
+        "srli" => Mnemonic::RShift(SRLI),
+        "srai" => Mnemonic::RShift(SRAI),
+        "addi" => Mnemonic::I(ADDI),
+        "slti" => Mnemonic::I(SLTI),
+        "sltiu" => Mnemonic::I(SLTIU),
+        "xori" => Mnemonic::I(XORI),
+        "ori" => Mnemonic::I(ORI),
+        "andi" => Mnemonic::I(ANDI),
+        "lb" => Mnemonic::Load(LB),
+        "lh" => Mnemonic::Load(LH),
+        "lw" => Mnemonic::Load(LW),
+        "lbu" => Mnemonic::Load(LBU),
+        "lhu" => Mnemonic::Load(LHU),
+        "jalr" => Mnemonic::Jalr,
+        "scall" | "ecall" => Mnemonic::Scall,
+        "sb" => Mnemonic::S(SB),
+        "sh" => Mnemonic::S(SH),
+        "sw" => Mnemonic::S(SW),
+        "beq" => Mnemonic::SB(BEQ),
+        "bne" => Mnemonic::SB(BNE),
+        "blt" => Mnemonic::SB(BLT),
+        "bge" => Mnemonic::SB(BGE),
+        "bltu" => Mnemonic::SB(BLTU),
+        "bgeu" => Mnemonic::SB(BGEU),
+        "lui" => Mnemonic::U(LUI),
+        "auipc" => Mnemonic::U(AUIPC),
+        "jal" => Mnemonic::Jal,
+        "nop" => Mnemonic::Pseudo(Pseudo::Nop),
+        "li" => Mnemonic::Pseudo(Pseudo::Li),
+        "la" => Mnemonic::Pseudo(Pseudo::La),
+        "mv" => Mnemonic::Pseudo(Pseudo::Mv),
+        "not" => Mnemonic::Pseudo(Pseudo::Not),
+        "neg" => Mnemonic::Pseudo(Pseudo::Neg),
+        "j" => Mnemonic::Pseudo(Pseudo::J),
+        "jr" => Mnemonic::Pseudo(Pseudo::Jr),
+        "ret" => Mnemonic::Pseudo(Pseudo::Ret),
+        "call" => Mnemonic::Pseudo(Pseudo::Call),
+        "tail" => Mnemonic::Pseudo(Pseudo::Tail),
+        "beqz" => Mnemonic::Pseudo(Pseudo::Beqz),
+        "bnez" => Mnemonic::Pseudo(Pseudo::Bnez),
+        "bgt" => Mnemonic::Pseudo(Pseudo::Bgt),
+        "ble" => Mnemonic::Pseudo(Pseudo::Ble),
+        "seqz" => Mnemonic::Pseudo(Pseudo::Seqz),
+        "snez" => Mnemonic::Pseudo(Pseudo::Snez),
+        _ => return None,
+    })
+}
+
+fn operand_count(mnemonic: Mnemonic, found: usize) -> usize {
+    match mnemonic {
+        Mnemonic::Scall => 0,
+        Mnemonic::Load(_) | Mnemonic::S(_) | Mnemonic::U(_) | Mnemonic::Jal => 2,
+        Mnemonic::Jalr if found == 2 => 2,
+        Mnemonic::Pseudo(pseudo) => match pseudo {
+            Pseudo::Nop | Pseudo::Ret => 0,
+            Pseudo::J | Pseudo::Jr | Pseudo::Call | Pseudo::Tail => 1,
+            Pseudo::Bgt | Pseudo::Ble => 3,
+            _ => 2,
+        },
+        _ => 3,
+    }
+}
+
+fn fits_addi(value: u32) -> bool {
+    let value = value as i32;
+    value >= -2048 && value <= 2047
+}
+
+/// Splits a value into a lui/auipc immediate and a sign-extended addi
+/// immediate that add up to it.
+fn split_upper(value: u32) -> (u32, u32) {
+    let upper = (value.wrapping_add(0x800) >> 12) & 0xFFFFF;
+    (upper, value.wrapping_sub(upper << 12))
+}
+
+/// How many instructions a statement assembles to, which the first
+/// pass needs to lay out labels. Also checks the operand count.
+fn expansion_length(mnemonic: Mnemonic, operands: &[Operand], span: Span) -> Result<usize> {
+    let expected = operand_count(mnemonic, operands.len());
+    if operands.len() != expected {
+        let plural = if expected == 1 { "" } else { "s" };
+        return Err(Error::new(span, format!("expected {} operand{}, found {}", expected, plural, operands.len())));
+    }
+
+    Ok(match mnemonic {
+        Mnemonic::Pseudo(Pseudo::La) | Mnemonic::Pseudo(Pseudo::Call) | Mnemonic::Pseudo(Pseudo::Tail) => 2,
+        Mnemonic::Pseudo(Pseudo::Li) => match operands[1].kind {
+            // Out-of-range constants are reported in the second pass
+            OperandKind::Immediate(value) => {
+                let value = value as u32;
+                if fits_addi(value) || split_upper(value).1 == 0 { 1 } else { 2 }
+            }
+            _ => 1,
+        },
+        _ => 1,
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Section {
+    Text,
+    Data,
+}
+
+/// Something the second pass has to fill in once labels are known.
+struct Pending {
+    // the mnemonic, or the value for data
+    span: Span,
+    section: Section,
+    offset: usize,
+    kind: PendingKind,
+}
+
+enum PendingKind {
+    Instruction(Mnemonic, Vec<Operand>),
+    // a label used as the value of a .word, .half or .byte
+    Data(usize, Operand),
+}
+
+struct Assembler {
+    section: Section,
+    text: Vec<u8>,
+    data: Vec<u8>,
+    // label: (section, offset)
+    labels: HashMap<String, (Section, usize)>,
+    pending: Vec<Pending>,
+}
+
+fn push_le(bytes: &mut Vec<u8>, value: u32, size: usize) {
+    for i in 0..size {
+        bytes.push((value >> (8 * i)) as u8);
+    }
+}
+
+/// Checks that `value` fits in `bytes` bytes as either a signed or an
+/// unsigned number.
+fn check_data(value: i64, size: usize, operand: &Operand) -> Result<u32> {
+    let bits = 8 * size as u32;
+    if value < -(1i64 << (bits - 1)) || value >= (1i64 << bits) {
+        return Err(Error::new(operand.span, format!("value {} does not fit in {} bits", value, bits)));
+    }
+    Ok(value as u32)
+}
+
+impl Assembler {
+    fn new() -> Assembler {
+        Assembler {
+            section: Section::Text,
+            text: vec![],
+            data: vec![],
+            labels: HashMap::new(),
+            pending: vec![],
+        }
+    }
+
+    fn bytes(&mut self) -> &mut Vec<u8> {
+        match self.section {
+            Section::Text => &mut self.text,
+            Section::Data => &mut self.data,
+        }
+    }
+
+    /// First pass over one line: defines its labels and reserves space
+    /// for whatever it emits.
+    fn layout_line(&mut self, line: &str, number: usize) -> Result<()> {
+        let tokens = try!(tokenize(line, number));
+        let mut rest = &tokens[..];
+
+        while rest.len() >= 2 && rest[1].kind == TokenKind::Colon {
+            let label = match rest[0].kind {
+                TokenKind::Ident(ref label) if parse_register(label).is_none() => label.clone(),
+                _ => return Err(Error::new(rest[0].span, "invalid label")),
+            };
+            if self.labels.contains_key(&label) {
+                return Err(Error::new(rest[0].span, format!("label {} is already defined", label)));
+            }
+            let offset = self.bytes().len();
+            self.labels.insert(label, (self.section, offset));
+            rest = &rest[2..];
+        }
+
+        if rest.is_empty() {
+            return Ok(());
+        }
+        let name = match rest[0].kind {
+            TokenKind::Ident(ref name) => name.to_lowercase(),
+            _ => return Err(Error::new(rest[0].span, "expected an instruction or directive")),
+        };
+        let end = Span::new(number, line.chars().count() + 1, 1);
+        let operands = try!(parse_operands(&rest[1..], end));
+
+        if name.starts_with('.') {
+            self.directive(&name, operands, rest[0].span)
+        }
+        else {
+            self.instruction(&name, operands, rest[0].span)
+        }
+    }
+
+    fn directive(&mut self, name: &str, operands: Vec<Operand>, span: Span) -> Result<()> {
+        match name {
+            ".text" => self.section = Section::Text,
+            ".data" => self.section = Section::Data,
+            ".globl" | ".global" => {}
+            ".word" | ".half" | ".byte" => {
+                let size = match name { ".word" => 4, ".half" => 2, _ => 1 };
+                if operands.is_empty() {
+                    return Err(Error::new(span, format!("{} needs at least one value", name)));
+                }
+                for operand in operands {
+                    match operand.kind {
+                        OperandKind::Immediate(value) => {
+                            let value = try!(check_data(value, size, &operand));
+                            push_le(self.bytes(), value, size);
+                        }
+                        OperandKind::Label(_) => {
+                            let offset = self.bytes().len();
+                            self.pending.push(Pending {
+                                span: operand.span,
+                                section: self.section,
+                                offset: offset,
+                                kind: PendingKind::Data(size, operand),
+                            });
+                            push_le(self.bytes(), 0, size);
+                        }
+                        _ => return Err(Error::new(operand.span, "expected a number or label")),
+                    }
+                }
+            }
+            ".asciz" | ".string" => {
+                if operands.is_empty() {
+                    return Err(Error::new(span, format!("{} needs a string", name)));
+                }
+                for operand in operands {
+                    match operand.kind {
+                        OperandKind::Str(ref text) => {
+                            self.bytes().extend(text.bytes());
+                            self.bytes().push(0);
+                        }
+                        _ => return Err(Error::new(operand.span, "expected a string")),
+                    }
+                }
+            }
+            ".space" | ".align" => {
+                let amount = match operands.first().map(|operand| &operand.kind) {
+                    Some(&OperandKind::Immediate(amount)) if operands.len() == 1 && amount >= 0 => amount as usize,
+                    _ => return Err(Error::new(span, format!("{} needs one non-negative number", name))),
+                };
+                if name == ".space" {
+                    let length = self.bytes().len() + amount;
+                    self.bytes().resize(length, 0);
+                }
+                else {
+                    if amount > 12 {
+                        return Err(Error::new(operands[0].span, "alignment is too large"));
+                    }
+                    let alignment = 1 << amount;
+                    while self.bytes().len() % alignment != 0 {
+                        self.bytes().push(0);
+                    }
+                }
+            }
+            _ => return Err(Error::new(span, format!("unknown directive {}", name))),
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, name: &str, operands: Vec<Operand>, span: Span) -> Result<()> {
+        let mnemonic = match mnemonic(name) {
+            Some(mnemonic) => mnemonic,
+            None => return Err(Error::new(span, format!("unknown instruction {}", name))),
+        };
+        if self.section != Section::Text {
+            return Err(Error::new(span, "instructions must be in the .text section"));
+        }
+        if self.text.len() % 4 != 0 {
+            return Err(Error::new(span, "instruction is not word-aligned"));
+        }
+
+        let offset = self.text.len();
+        let size = 4 * try!(expansion_length(mnemonic, &operands, span));
+        self.pending.push(Pending {
+            span: span,
+            section: Section::Text,
+            offset: offset,
+            kind: PendingKind::Instruction(mnemonic, operands),
+        });
+        self.text.resize(offset + size, 0);
+        Ok(())
+    }
+
+    fn data_start(&self) -> usize {
+        (self.text.len() + 3) & !3
+    }
+
+    fn address(&self, section: Section, offset: usize) -> u32 {
+        (match section {
+            Section::Text => offset,
+            Section::Data => self.data_start() + offset,
+        }) as u32
+    }
+
+    fn lookup(&self, label: &str, span: Span) -> Result<u32> {
+        match self.labels.get(label) {
+            Some(&(section, offset)) => Ok(self.address(section, offset)),
+            None => Err(Error::new(span, format!("undefined label {}", label))),
+        }
+    }
+
+    /// Second pass: fills in one pending instruction or value.
+    fn resolve(&mut self, pending: &Pending) -> Result<()> {
+        let pc = self.address(pending.section, pending.offset);
+        // (value, size in bytes)
+        let mut values = vec![];
+        match pending.kind {
+            PendingKind::Data(size, ref operand) => {
+                let value = match operand.kind {
+                    OperandKind::Label(ref label) => try!(self.lookup(label, operand.span)),
+                    _ => unreachable!(),
+                };
+                values.push((try!(check_data(value as i64, size, operand)), size));
+            }
+            PendingKind::Instruction(mnemonic, ref operands) => {
+                for instruction in try!(self.build(mnemonic, operands, pc)) {
+                    match binary::encode(&instruction) {
+                        Ok(word) => values.push((word, 4)),
+                        Err(err) => return Err(Error::new(pending.span, format!("{}", err))),
+                    }
+                }
+            }
+        }
+
+        let bytes = match pending.section {
+            Section::Text => &mut self.text,
+            Section::Data => &mut self.data,
+        };
+        let mut offset = pending.offset;
+        for (value, size) in values {
+            for i in 0..size {
+                bytes[offset + i] = (value >> (8 * i)) as u8;
+            }
+            offset += size;
+        }
+        Ok(())
+    }
+
+    fn register(&self, operand: &Operand) -> Result<Register> {
+        match operand.kind {
+            OperandKind::Register(register) => Ok(register),
+            _ => Err(Error::new(operand.span, "expected a register")),
+        }
+    }
+
+    /// The value of a number or label operand, checked against the
+    /// inclusive range.
+    fn immediate(&self, operand: &Operand, min: i64, max: i64) -> Result<u32> {
+        let value = match operand.kind {
+            OperandKind::Immediate(value) => value,
+            OperandKind::Label(ref label) => try!(self.lookup(label, operand.span)) as i64,
+            _ => return Err(Error::new(operand.span, "expected a number or label")),
+        };
+        if value < min || value > max {
+            return Err(Error::new(operand.span,
+                                  format!("immediate {} is out of range ({} to {})", value, min, max)));
+        }
+        Ok(value as u32)
+    }
+
+    /// A branch or jump target: labels become offsets from `pc`, and
+    /// numbers are taken as offsets already.
+    fn offset(&self, operand: &Operand, pc: u32, bits: u32) -> Result<u32> {
+        let offset = match operand.kind {
+            OperandKind::Immediate(offset) => offset,
+            OperandKind::Label(ref label) => try!(self.lookup(label, operand.span)) as i64 - pc as i64,
+            _ => return Err(Error::new(operand.span, "expected a label or offset")),
+        };
+        let limit = 1i64 << (bits - 1);
+        if offset < -limit || offset >= limit {
+            return Err(Error::new(operand.span, format!("target is out of range (offset {})", offset)));
+        }
+        if offset % 2 != 0 {
+            return Err(Error::new(operand.span, format!("offset {} is not a multiple of 2", offset)));
+        }
+        Ok(offset as u32)
+    }
+
+    fn memory(&self, operand: &Operand) -> Result<(i64, Register)> {
+        match operand.kind {
+            OperandKind::Memory(offset, base) => {
+                if offset < -2048 || offset > 2047 {
+                    return Err(Error::new(operand.span,
+                                          format!("offset {} is out of range (-2048 to 2047)", offset)));
+                }
+                Ok((offset, base))
+            }
+            _ => Err(Error::new(operand.span, "expected offset(register)")),
+        }
+    }
+
+    /// A label's address relative to `pc`, split for an auipc and the
+    /// addi or jalr that follows it.
+    fn pc_relative(&self, operand: &Operand, pc: u32) -> Result<(u32, u32)> {
+        match operand.kind {
+            OperandKind::Label(ref label) => {
+                let target = try!(self.lookup(label, operand.span));
+                Ok(split_upper(target.wrapping_sub(pc)))
+            }
+            _ => Err(Error::new(operand.span, "expected a label")),
+        }
+    }
+
+    /// Builds the instructions for a statement. The operand count was
+    /// already checked by the first pass.
+    fn build(&self, mnemonic: Mnemonic, operands: &[Operand], pc: u32) -> Result<Vec<Instruction>> {
+        match mnemonic {
+            Mnemonic::Pseudo(pseudo) => self.build_pseudo(pseudo, operands, pc),
+            _ => self.build_one(mnemonic, operands, pc).map(|instruction| vec![instruction]),
+        }
+    }
+
+    fn build_pseudo(&self, pseudo: Pseudo, operands: &[Operand], pc: u32) -> Result<Vec<Instruction>> {
+        use isa::IOpcode::*;
+
+        let i_type = |opcode, rd, rs1, imm| Instruction::I { opcode: opcode, rd: rd, rs1: rs1, imm: imm };
+        let branch = |opcode, rs1, rs2, target| -> Result<Vec<Instruction>> {
+            Ok(vec![Instruction::SB {
+                opcode: opcode,
+                rs1: rs1,
+                rs2: rs2,
+                imm: try!(self.offset(target, pc, 13)),
+            }])
+        };
+
+        Ok(match pseudo {
+            Pseudo::Nop => vec![i_type(ADDI, Register::X0, Register::X0, 0)],
+            Pseudo::Li => {
+                let rd = try!(self.register(&operands[0]));
+                let value = match operands[1].kind {
+                    OperandKind::Immediate(_) =>
+                        try!(self.immediate(&operands[1], -(1 << 31), (1 << 32) - 1)),
+                    _ => return Err(Error::new(operands[1].span,
+                                               "li needs a number; use la to load an address")),
+                };
+                if fits_addi(value) {
+                    return Ok(vec![i_type(ADDI, rd, Register::X0, value)]);
+                }
+                let (upper, lower) = split_upper(value);
+                let lui = Instruction::U { opcode: isa::UOpcode::LUI, rd: rd, imm: upper };
+                if lower == 0 { vec![lui] } else { vec![lui, i_type(ADDI, rd, rd, lower)] }
+            }
+            Pseudo::La => {
+                let rd = try!(self.register(&operands[0]));
+                let (upper, lower) = try!(self.pc_relative(&operands[1], pc));
+                vec![Instruction::U { opcode: isa::UOpcode::AUIPC, rd: rd, imm: upper },
+                     i_type(ADDI, rd, rd, lower)]
+            }
+            Pseudo::Mv | Pseudo::Not | Pseudo::Seqz => {
+                let (opcode, imm) = match pseudo {
+                    Pseudo::Mv => (ADDI, 0),
+                    Pseudo::Not => (XORI, !0),
+                    _ => (SLTIU, 1),
+                };
+                vec![i_type(opcode,
+                            try!(self.register(&operands[0])),
+                            try!(self.register(&operands[1])),
+                            imm)]
+            }
+            Pseudo::Neg | Pseudo::Snez => {
+                let opcode = if pseudo == Pseudo::Neg { isa::ROpcode::SUB } else { isa::ROpcode::SLTU };
+                vec![Instruction::R {
+                    opcode: opcode,
+                    rd: try!(self.register(&operands[0])),
+                    rs1: Register::X0,
+                    rs2: try!(self.register(&operands[1])),
+                }]
+            }
+            Pseudo::J => vec![Instruction::UJ {
+                opcode: isa::UJOpcode::JAL,
+                rd: Register::X0,
+                imm: try!(self.offset(&operands[0], pc, 21)),
+            }],
+            Pseudo::Jr => vec![i_type(JALR, Register::X0, try!(self.register(&operands[0])), 0)],
+            Pseudo::Ret => vec![i_type(JALR, Register::X0, Register::X1, 0)],
+            // call goes through ra, and tail through t1 so ra is kept
+            Pseudo::Call | Pseudo::Tail => {
+                let (link, scratch) = if pseudo == Pseudo::Call {
+                    (Register::X1, Register::X1)
+                }
+                else {
+                    (Register::X0, Register::X6)
+                };
+                let (upper, lower) = try!(self.pc_relative(&operands[0], pc));
+                vec![Instruction::U { opcode: isa::UOpcode::AUIPC, rd: scratch, imm: upper },
+                     i_type(JALR, link, scratch, lower)]
+            }
+            Pseudo::Beqz => try!(branch(isa::SBOpcode::BEQ, try!(self.register(&operands[0])),
+                                        Register::X0, &operands[1])),
+            Pseudo::Bnez => try!(branch(isa::SBOpcode::BNE, try!(self.register(&operands[0])),
+                                        Register::X0, &operands[1])),
+            // Swap the operands: a > b is b < a
+            Pseudo::Bgt => try!(branch(isa::SBOpcode::BLT, try!(self.register(&operands[1])),
+                                       try!(self.register(&operands[0])), &operands[2])),
+            Pseudo::Ble => try!(branch(isa::SBOpcode::BGE, try!(self.register(&operands[1])),
+                                       try!(self.register(&operands[0])), &operands[2])),
+        })
+    }
+
+    fn build_one(&self, mnemonic: Mnemonic, operands: &[Operand], pc: u32) -> Result<Instruction> {
+        Ok(match mnemonic {
+            Mnemonic::R(opcode) => Instruction::R {
+                opcode: opcode,
+                rd: try!(self.register(&operands[0])),
+                rs1: try!(self.register(&operands[1])),
+                rs2: try!(self.register(&operands[2])),
+            },
+            Mnemonic::RShift(opcode) => Instruction::RShift {
+                opcode: opcode,
+                rd: try!(self.register(&operands[0])),
+                rs1: try!(self.register(&operands[1])),
+                shamt: try!(self.immediate(&operands[2], 0, 31)),
+            },
+            Mnemonic::I(opcode) => Instruction::I {
+                opcode: opcode,
+                rd: try!(self.register(&operands[0])),
+                rs1: try!(self.register(&operands[1])),
+                imm: try!(self.immediate(&operands[2], -2048, 2047)),
+            },
+            Mnemonic::Load(opcode) => {
+                let (offset, base) = try!(self.memory(&operands[1]));
+                Instruction::I {
+                    opcode: opcode,
+                    rd: try!(self.register(&operands[0])),
+                    rs1: base,
+                    imm: offset as u32,
+                }
+            }
+            // Both jalr rd, rs1, imm and jalr rd, imm(rs1)
+            Mnemonic::Jalr if operands.len() == 2 => {
+                let (offset, base) = try!(self.memory(&operands[1]));
+                Instruction::I {
+                    opcode: isa::IOpcode::JALR,
+                    rd: try!(self.register(&operands[0])),
+                    rs1: base,
+                    imm: offset as u32,
+                }
+            }
+            Mnemonic::Jalr => Instruction::I {
+                opcode: isa::IOpcode::JALR,
+                rd: try!(self.register(&operands[0])),
+                rs1: try!(self.register(&operands[1])),
+                imm: try!(self.immediate(&operands[2], -2048, 2047)),
+            },
+            Mnemonic::Scall => Instruction::I {
+                opcode: isa::IOpcode::SCALL,
+                rd: Register::X0,
+                rs1: Register::X0,
+                imm: 0,
+            },
+            Mnemonic::S(opcode) => {
+                let (offset, base) = try!(self.memory(&operands[1]));
+                Instruction::S {
+                    opcode: opcode,
+                    rs1: base,
+                    rs2: try!(self.register(&operands[0])),
+                    imm: offset as u32,
+                }
+            }
+            Mnemonic::SB(opcode) => Instruction::SB {
+                opcode: opcode,
+                rs1: try!(self.register(&operands[0])),
+                rs2: try!(self.register(&operands[1])),
+                imm: try!(self.offset(&operands[2], pc, 13)),
+            },
+            Mnemonic::U(opcode) => Instruction::U {
+                opcode: opcode,
+                rd: try!(self.register(&operands[0])),
+                imm: try!(self.immediate(&operands[1], 0, 0xFFFFF)),
+            },
+            Mnemonic::Jal => Instruction::UJ {
+                opcode: isa::UJOpcode::JAL,
+                rd: try!(self.register(&operands[0])),
+                imm: try!(self.offset(&operands[1], pc, 21)),
+            },
+            Mnemonic::Pseudo(_) => unreachable!(),
+        })
+    }
+
+    fn finish(self) -> Image {
+        let data_start = self.data_start();
+        let mut bytes = self.text.clone();
+        bytes.resize(data_start, 0);
+        bytes.extend(&self.data);
+
+        let words = bytes.chunks(4).map(|chunk| {
+            chunk.iter().enumerate().fold(0, |word, (i, &byte)| word | ((byte as u32) << (8 * i)))
+        }).collect();
+        let labels = self.labels.iter().map(|(label, &(section, offset))| {
+            (label.clone(), self.address(section, offset))
+        }).collect();
+
+        Image {
+            words: words,
+            text_size: self.text.len() as u32,
+            data_start: data_start as u32,
+            labels: labels,
+        }
+    }
+}
+
+/// Assembles RV32I source into a memory image. The second pass
+/// (resolving labels and encoding) still checks every statement the
+/// first pass could lay out, so the errors from both are reported
+/// together.
+pub fn assemble(input: &str) -> ::std::result::Result<Image, Vec<Error>> {
+    let mut assembler = Assembler::new();
+    let mut errors = vec![];
+    for (i, line) in input.lines().enumerate() {
+        if let Err(err) = assembler.layout_line(line, i + 1) {
+            errors.push(err);
+        }
+    }
+
+    let pending = ::std::mem::replace(&mut assembler.pending, vec![]);
+    for item in pending.iter() {
+        if let Err(err) = assembler.resolve(item) {
+            errors.push(err);
+        }
+    }
+    if !errors.is_empty() {
+        // In source order, rather than by pass
+        errors.sort_by_key(|err| (err.span.line, err.span.column));
+        return Err(errors);
+    }
+
+    Ok(assembler.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use binary;
+    use isa::{self, Instruction, Register};
+
+    use super::{assemble, split_upper, Image};
+
+    fn assemble_ok(source: &str) -> Image {
+        match assemble(source) {
+            Ok(image) => image,
+            Err(errors) => {
+                let rendered: Vec<String> = errors.iter().map(|err| err.render("test.s", source)).collect();
+                panic!("\n{}", rendered.join("\n"));
+            }
+        }
+    }
+
+    fn decode_all(words: &[u32]) -> Vec<Instruction> {
+        words.iter().map(|&word| binary::decode(word).unwrap()).collect()
+    }
+
+    /// The instructions in the image's text section.
+    fn text(image: &Image) -> Vec<Instruction> {
+        decode_all(&image.words[..image.text_size as usize / 4])
+    }
+
+    fn addi(rd: Register, rs1: Register, imm: i32) -> Instruction {
+        Instruction::I { opcode: isa::IOpcode::ADDI, rd: rd, rs1: rs1, imm: imm as u32 }
+    }
+
+    #[test]
+    fn branches_to_labels() {
+        let image = assemble_ok("
+        start:
+            beq x1, x2, end
+            addi x1, x1, 1
+        end:
+            bne x1, x2, start
+            jal ra, end");
+        assert_eq!(text(&image), vec![
+            Instruction::SB { opcode: isa::SBOpcode::BEQ, rs1: Register::X1, rs2: Register::X2, imm: 8 },
+            addi(Register::X1, Register::X1, 1),
+            Instruction::SB {
+                opcode: isa::SBOpcode::BNE,
+                rs1: Register::X1,
+                rs2: Register::X2,
+                imm: -8i32 as u32,
+            },
+            Instruction::UJ { opcode: isa::UJOpcode::JAL, rd: Register::X1, imm: -4i32 as u32 },
+        ]);
+        assert_eq!(image.labels["start"], 0);
+        assert_eq!(image.labels["end"], 8);
+    }
+
+    #[test]
+    fn sections() {
+        let image = assemble_ok("
+            .data
+        first:
+            .word 1
+            .text
+        main:
+            addi x1, x0, 1
+            lw x2, 0(x1)
+            .data
+        second:
+            .byte 2");
+        assert_eq!(image.text_size, 8);
+        assert_eq!(image.data_start, 8);
+        assert_eq!(image.labels["main"], 0);
+        assert_eq!(image.labels["first"], 8);
+        assert_eq!(image.labels["second"], 12);
+        assert_eq!(&image.words[2..], &[1, 2]);
+        assert_eq!(text(&image), vec![
+            addi(Register::X1, Register::X0, 1),
+            Instruction::I { opcode: isa::IOpcode::LW, rd: Register::X2, rs1: Register::X1, imm: 0 },
+        ]);
+    }
+
+    #[test]
+    fn data_directives() {
+        let image = assemble_ok("
+            .text
+            .byte 0xFF
+            .data
+            .byte 1
+            .align 2
+            .half 0x0302
+            .asciz \"ab\"
+            .space 3
+        pointer:
+            .word pointer, -1");
+        // Data starts on the word after the stray text byte
+        assert_eq!(image.text_size, 1);
+        assert_eq!(image.data_start, 4);
+        assert_eq!(image.labels["pointer"], 16);
+        assert_eq!(image.words, vec![0xFF, 0x00000001, 0x62610302, 0, 16, 0xFFFFFFFF]);
+    }
+
+    #[test]
+    fn decodes_to_source() {
+        let image = assemble_ok("
+            lui t0, 0x12345
+            sw t0, -4(sp)
+            lbu a0, 3(t0)
+            srai a1, a0, 31
+            sub a2, a1, a0
+            jalr zero, ra, 0
+            ecall");
+        assert_eq!(text(&image), vec![
+            Instruction::U { opcode: isa::UOpcode::LUI, rd: Register::X5, imm: 0x12345 },
+            Instruction::S {
+                opcode: isa::SOpcode::SW,
+                rs1: Register::X2,
+                rs2: Register::X5,
+                imm: -4i32 as u32,
+            },
+            Instruction::I { opcode: isa::IOpcode::LBU, rd: Register::X10, rs1: Register::X5, imm: 3 },
+            Instruction::RShift {
+                opcode: isa::RShiftOpcode::SRAI,
+                rd: Register::X11,
+                rs1: Register::X10,
+                shamt: 31,
+            },
+            Instruction::R {
+                opcode: isa::ROpcode::SUB,
+                rd: Register::X12,
+                rs1: Register::X11,
+                rs2: Register::X10,
+            },
+            Instruction::I { opcode: isa::IOpcode::JALR, rd: Register::X0, rs1: Register::X1, imm: 0 },
+            Instruction::I { opcode: isa::IOpcode::SCALL, rd: Register::X0, rs1: Register::X0, imm: 0 },
+        ]);
+    }
+
+    fn lui(rd: Register, imm: u32) -> Instruction {
+        Instruction::U { opcode: isa::UOpcode::LUI, rd: rd, imm: imm }
+    }
+
+    fn auipc(rd: Register, imm: u32) -> Instruction {
+        Instruction::U { opcode: isa::UOpcode::AUIPC, rd: rd, imm: imm }
+    }
+
+    fn jalr(rd: Register, rs1: Register, imm: i32) -> Instruction {
+        Instruction::I { opcode: isa::IOpcode::JALR, rd: rd, rs1: rs1, imm: imm as u32 }
+    }
+
+    #[test]
+    fn split_upper_rounds() {
+        assert_eq!(split_upper(0x12345678), (0x12345, 0x678));
+        // A low part with bit 11 set is negative once sign-extended, so
+        // the upper part has to make up for it
+        assert_eq!(split_upper(0x12345800), (0x12346, -0x800i32 as u32));
+        assert_eq!(split_upper(0xFFFFFFFF), (0, -1i32 as u32));
+    }
+
+    #[test]
+    fn li() {
+        let image = assemble_ok("
+            li a0, 5
+            li a0, -2048
+            li a0, 0x12345000
+            li a0, 0x12345678
+            li a0, 0x12345FFF
+            li a0, 0xFFFFF800");
+        assert_eq!(text(&image), vec![
+            addi(Register::X10, Register::X0, 5),
+            addi(Register::X10, Register::X0, -2048),
+            lui(Register::X10, 0x12345),
+            lui(Register::X10, 0x12345),
+            addi(Register::X10, Register::X10, 0x678),
+            lui(Register::X10, 0x12346),
+            addi(Register::X10, Register::X10, -1),
+            // Fits in an addi once sign-extended
+            addi(Register::X10, Register::X0, -2048),
+        ]);
+    }
+
+    #[test]
+    fn pc_relative_pseudos() {
+        // The target is far enough away that each auipc has to round up
+        let image = assemble_ok("
+            nop
+            la a0, target
+            call target
+            tail target
+            .space 0x800
+        target:
+            ret");
+        assert_eq!(image.labels["target"], 0x81C);
+        assert_eq!(decode_all(&image.words[..7]), vec![
+            addi(Register::X0, Register::X0, 0),
+            auipc(Register::X10, 1),
+            addi(Register::X10, Register::X10, 0x818 - 0x1000),
+            auipc(Register::X1, 1),
+            jalr(Register::X1, Register::X1, 0x810 - 0x1000),
+            auipc(Register::X6, 1),
+            jalr(Register::X0, Register::X6, 0x808 - 0x1000),
+        ]);
+    }
+
+    #[test]
+    fn swapped_branches() {
+        let image = assemble_ok("
+        there:
+            bgt a0, a1, there
+            ble a0, a1, there");
+        assert_eq!(text(&image), vec![
+            Instruction::SB { opcode: isa::SBOpcode::BLT, rs1: Register::X11, rs2: Register::X10, imm: 0 },
+            Instruction::SB {
+                opcode: isa::SBOpcode::BGE,
+                rs1: Register::X11,
+                rs2: Register::X10,
+                imm: -4i32 as u32,
+            },
+        ]);
+    }
+
+    #[test]
+    fn single_instruction_pseudos() {
+        let image = assemble_ok("
+            seqz a0, a1
+            snez a0, a1
+            not a0, a1
+            neg a0, a1
+            mv a0, a1");
+        assert_eq!(text(&image), vec![
+            Instruction::I { opcode: isa::IOpcode::SLTIU, rd: Register::X10, rs1: Register::X11, imm: 1 },
+            Instruction::R {
+                opcode: isa::ROpcode::SLTU,
+                rd: Register::X10,
+                rs1: Register::X0,
+                rs2: Register::X11,
+            },
+            Instruction::I { opcode: isa::IOpcode::XORI, rd: Register::X10, rs1: Register::X11, imm: !0 },
+            Instruction::R {
+                opcode: isa::ROpcode::SUB,
+                rd: Register::X10,
+                rs1: Register::X0,
+                rs2: Register::X11,
+            },
+            addi(Register::X10, Register::X11, 0),
+        ]);
+    }
+
+    #[test]
+    fn uppercase() {
+        let image = assemble_ok("
+            ADDI A0, ZERO, 1
+            LW T0, 4(SP)");
+        assert_eq!(text(&image), vec![
+            addi(Register::X10, Register::X0, 1),
+            Instruction::I { opcode: isa::IOpcode::LW, rd: Register::X5, rs1: Register::X2, imm: 4 },
+        ]);
+    }
+
+    #[test]
+    fn errors() {
+        let source = concat!("main:\n",
+                             "    addi x1, x0, 5000\n",
+                             "    bogus x1\n",
+                             "    j nowhere\n",
+                             "    add x1, x2\n",
+                             "\tlw x1, 9999(x2)\n");
+        let errors = match assemble(source) {
+            Ok(_) => panic!("assembled with errors"),
+            Err(errors) => errors,
+        };
+        let rendered: Vec<String> = errors.iter().map(|err| err.render("test.s", source)).collect();
+        // Both passes' errors, in source order
+        assert_eq!(rendered, vec![
+            "test.s:2:18: error: immediate 5000 is out of range (-2048 to 2047)\n    addi x1, x0, 5000\n                 ^^^^",
+            "test.s:3:5: error: unknown instruction bogus\n    bogus x1\n    ^^^^^",
+            "test.s:4:7: error: undefined label nowhere\n    j nowhere\n      ^^^^^^^",
+            "test.s:5:5: error: expected 3 operands, found 2\n    add x1, x2\n    ^^^",
+            // The tab is copied so the caret lines up
+            "test.s:6:9: error: offset 9999 is out of range (-2048 to 2047)\n\tlw x1, 9999(x2)\n\t       ^^^^^^^^",
+        ]);
+    }
 }
@@ -0,0 +1,575 @@
+use std::fmt;
+
+use isa::{self, Instruction, Register};
+
+// Major opcodes (the low 7 bits of every instruction)
+const OPCODE_LOAD: u32 = 0b0000011;
+const OPCODE_MISC_MEM: u32 = 0b0001111;
+const OPCODE_OP_IMM: u32 = 0b0010011;
+const OPCODE_AUIPC: u32 = 0b0010111;
+const OPCODE_STORE: u32 = 0b0100011;
+const OPCODE_OP: u32 = 0b0110011;
+const OPCODE_LUI: u32 = 0b0110111;
+const OPCODE_BRANCH: u32 = 0b1100011;
+const OPCODE_JALR: u32 = 0b1100111;
+const OPCODE_JAL: u32 = 0b1101111;
+const OPCODE_SYSTEM: u32 = 0b1110011;
+
+// funct7 for SUB and SRA(I)
+const FUNCT7_ALTERNATE: u32 = 0b0100000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DecodeError {
+    // The low 7 bits aren't an RV32I opcode
+    InvalidOpcode(u32),
+    // The opcode is valid, but funct3/funct7 don't pick an instruction
+    InvalidFunction(u32),
+    // Reserved bits that must be zero aren't
+    ReservedBits(u32),
+    // Valid RV32I that isa::Instruction can't represent (FENCE, EBREAK)
+    Unsupported(u32),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            DecodeError::InvalidOpcode(word) =>
+                write!(f, "{:08x}: invalid opcode {:07b}", word, opcode(word)),
+            DecodeError::InvalidFunction(word) =>
+                write!(f, "{:08x}: invalid function (funct3 {:03b}, funct7 {:07b}) for opcode {:07b}",
+                       word, funct3(word), funct7(word), opcode(word)),
+            DecodeError::ReservedBits(word) =>
+                write!(f, "{:08x}: reserved bits are set", word),
+            DecodeError::Unsupported(word) =>
+                write!(f, "{:08x}: instruction is not supported", word),
+        }
+    }
+}
+
+fn opcode(word: u32) -> u32 {
+    word & 0x7F
+}
+
+fn funct3(word: u32) -> u32 {
+    (word >> 12) & 0x7
+}
+
+fn funct7(word: u32) -> u32 {
+    word >> 25
+}
+
+fn register(bits: u32) -> Register {
+    // Any five bits name a register
+    Register::from_num(bits & 0x1F).unwrap()
+}
+
+fn rd(word: u32) -> Register {
+    register((word >> 7) & 0x1F)
+}
+
+fn rs1(word: u32) -> Register {
+    register((word >> 15) & 0x1F)
+}
+
+fn rs2(word: u32) -> Register {
+    register((word >> 20) & 0x1F)
+}
+
+/// Sign-extends the low `bits` bits of `value`.
+fn sign_extend(value: u32, bits: u32) -> u32 {
+    let shift = 32 - bits;
+    (((value << shift) as i32) >> shift) as u32
+}
+
+// The immediate of each format, reassembled and sign-extended
+
+fn i_immediate(word: u32) -> u32 {
+    sign_extend(word >> 20, 12)
+}
+
+fn s_immediate(word: u32) -> u32 {
+    sign_extend(((word >> 25) << 5) | ((word >> 7) & 0x1F), 12)
+}
+
+fn sb_immediate(word: u32) -> u32 {
+    let imm = (((word >> 31) & 0x1) << 12) |
+        (((word >> 7) & 0x1) << 11) |
+        (((word >> 25) & 0x3F) << 5) |
+        (((word >> 8) & 0xF) << 1);
+    sign_extend(imm, 13)
+}
+
+fn u_immediate(word: u32) -> u32 {
+    word >> 12
+}
+
+fn uj_immediate(word: u32) -> u32 {
+    let imm = (((word >> 31) & 0x1) << 20) |
+        (((word >> 12) & 0xFF) << 12) |
+        (((word >> 20) & 0x1) << 11) |
+        (((word >> 21) & 0x3FF) << 1);
+    sign_extend(imm, 21)
+}
+
+fn decode_load(word: u32) -> Result<Instruction, DecodeError> {
+    let opcode = match funct3(word) {
+        0b000 => isa::IOpcode::LB,
+        0b001 => isa::IOpcode::LH,
+        0b010 => isa::IOpcode::LW,
+        0b100 => isa::IOpcode::LBU,
+        0b101 => isa::IOpcode::LHU,
+        _ => return Err(DecodeError::InvalidFunction(word)),
+    };
+
+    Ok(Instruction::I { opcode: opcode, rd: rd(word), rs1: rs1(word), imm: i_immediate(word) })
+}
+
+fn decode_op_imm(word: u32) -> Result<Instruction, DecodeError> {
+    let shift = match (funct3(word), funct7(word)) {
+        (0b001, 0) => Some(isa::RShiftOpcode::SLLI),
+        (0b101, 0) => Some(isa::RShiftOpcode::SRLI),
+        (0b101, FUNCT7_ALTERNATE) => Some(isa::RShiftOpcode::SRAI),
+        (0b001, _) | (0b101, _) => return Err(DecodeError::InvalidFunction(word)),
+        _ => None,
+    };
+    if let Some(opcode) = shift {
+        return Ok(Instruction::RShift {
+            opcode: opcode,
+            rd: rd(word),
+            rs1: rs1(word),
+            shamt: (word >> 20) & 0x1F,
+        });
+    }
+
+    let opcode = match funct3(word) {
+        0b000 => isa::IOpcode::ADDI,
+        0b010 => isa::IOpcode::SLTI,
+        0b011 => isa::IOpcode::SLTIU,
+        0b100 => isa::IOpcode::XORI,
+        0b110 => isa::IOpcode::ORI,
+        0b111 => isa::IOpcode::ANDI,
+        _ => unreachable!(),
+    };
+
+    Ok(Instruction::I { opcode: opcode, rd: rd(word), rs1: rs1(word), imm: i_immediate(word) })
+}
+
+fn decode_store(word: u32) -> Result<Instruction, DecodeError> {
+    let opcode = match funct3(word) {
+        0b000 => isa::SOpcode::SB,
+        0b001 => isa::SOpcode::SH,
+        0b010 => isa::SOpcode::SW,
+        _ => return Err(DecodeError::InvalidFunction(word)),
+    };
+
+    Ok(Instruction::S { opcode: opcode, rs1: rs1(word), rs2: rs2(word), imm: s_immediate(word) })
+}
+
+fn decode_op(word: u32) -> Result<Instruction, DecodeError> {
+    let opcode = match (funct3(word), funct7(word)) {
+        (0b000, 0) => isa::ROpcode::ADD,
+        (0b000, FUNCT7_ALTERNATE) => isa::ROpcode::SUB,
+        (0b001, 0) => isa::ROpcode::SLL,
+        (0b010, 0) => isa::ROpcode::SLT,
+        (0b011, 0) => isa::ROpcode::SLTU,
+        (0b100, 0) => isa::ROpcode::XOR,
+        (0b101, 0) => isa::ROpcode::SRL,
+        (0b101, FUNCT7_ALTERNATE) => isa::ROpcode::SRA,
+        (0b110, 0) => isa::ROpcode::OR,
+        (0b111, 0) => isa::ROpcode::AND,
+        _ => return Err(DecodeError::InvalidFunction(word)),
+    };
+
+    Ok(Instruction::R { opcode: opcode, rd: rd(word), rs1: rs1(word), rs2: rs2(word) })
+}
+
+fn decode_branch(word: u32) -> Result<Instruction, DecodeError> {
+    let opcode = match funct3(word) {
+        0b000 => isa::SBOpcode::BEQ,
+        0b001 => isa::SBOpcode::BNE,
+        0b100 => isa::SBOpcode::BLT,
+        0b101 => isa::SBOpcode::BGE,
+        0b110 => isa::SBOpcode::BLTU,
+        0b111 => isa::SBOpcode::BGEU,
+        _ => return Err(DecodeError::InvalidFunction(word)),
+    };
+
+    Ok(Instruction::SB { opcode: opcode, rs1: rs1(word), rs2: rs2(word), imm: sb_immediate(word) })
+}
+
+fn decode_system(word: u32) -> Result<Instruction, DecodeError> {
+    if funct3(word) != 0 {
+        // CSR instructions aren't part of RV32I proper
+        return Err(DecodeError::Unsupported(word));
+    }
+    if (word >> 7) & 0x1F != 0 || (word >> 15) & 0x1F != 0 {
+        return Err(DecodeError::ReservedBits(word));
+    }
+
+    match word >> 20 {
+        0 => Ok(Instruction::I {
+            opcode: isa::IOpcode::SCALL,
+            rd: Register::X0,
+            rs1: Register::X0,
+            imm: 0,
+        }),
+        // SBREAK
+        1 => Err(DecodeError::Unsupported(word)),
+        _ => Err(DecodeError::InvalidFunction(word)),
+    }
+}
+
+/// Decodes a 32-bit RV32I machine word.
+pub fn decode(word: u32) -> Result<Instruction, DecodeError> {
+    // 16-bit (compressed) and longer encodings don't end in 0b11
+    if word & 0b11 != 0b11 {
+        return Err(DecodeError::InvalidOpcode(word));
+    }
+
+    match opcode(word) {
+        OPCODE_LOAD => decode_load(word),
+        OPCODE_OP_IMM => decode_op_imm(word),
+        OPCODE_STORE => decode_store(word),
+        OPCODE_OP => decode_op(word),
+        OPCODE_BRANCH => decode_branch(word),
+        OPCODE_SYSTEM => decode_system(word),
+        OPCODE_AUIPC => Ok(Instruction::U { opcode: isa::UOpcode::AUIPC, rd: rd(word), imm: u_immediate(word) }),
+        OPCODE_LUI => Ok(Instruction::U { opcode: isa::UOpcode::LUI, rd: rd(word), imm: u_immediate(word) }),
+        OPCODE_JALR => {
+            if funct3(word) != 0 {
+                return Err(DecodeError::InvalidFunction(word));
+            }
+            Ok(Instruction::I { opcode: isa::IOpcode::JALR, rd: rd(word), rs1: rs1(word), imm: i_immediate(word) })
+        },
+        OPCODE_JAL => Ok(Instruction::UJ { opcode: isa::UJOpcode::JAL, rd: rd(word), imm: uj_immediate(word) }),
+        OPCODE_MISC_MEM => Err(DecodeError::Unsupported(word)),
+        _ => Err(DecodeError::InvalidOpcode(word)),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EncodeError {
+    // The immediate doesn't fit in the format's field
+    ImmediateOutOfRange(i64),
+    // Branch and jump offsets must be a multiple of 2
+    MisalignedImmediate(i64),
+    ShiftOutOfRange(u32),
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            EncodeError::ImmediateOutOfRange(imm) =>
+                write!(f, "immediate {} is out of range", imm),
+            EncodeError::MisalignedImmediate(imm) =>
+                write!(f, "offset {} is not a multiple of 2", imm),
+            EncodeError::ShiftOutOfRange(shamt) =>
+                write!(f, "shift amount {} is out of range (0 to 31)", shamt),
+        }
+    }
+}
+
+/// Checks that a sign-extended immediate fits in `bits` bits, and
+/// that its low bit is clear if it must be `aligned`.
+fn check_signed(imm: u32, bits: u32, aligned: bool) -> Result<u32, EncodeError> {
+    let value = imm as i32 as i64;
+    let limit = 1i64 << (bits - 1);
+    if value < -limit || value >= limit {
+        return Err(EncodeError::ImmediateOutOfRange(value));
+    }
+    if aligned && value & 1 != 0 {
+        return Err(EncodeError::MisalignedImmediate(value));
+    }
+    // Keep only the field's bits
+    Ok(imm & ((1 << bits) - 1))
+}
+
+fn r_type(opcode: u32, funct3: u32, funct7: u32, rd: Register, rs1: Register, rs2: Register) -> u32 {
+    (funct7 << 25) | ((rs2.as_num() as u32) << 20) | ((rs1.as_num() as u32) << 15) |
+        (funct3 << 12) | ((rd.as_num() as u32) << 7) | opcode
+}
+
+fn i_type(opcode: u32, funct3: u32, rd: Register, rs1: Register, imm: u32) -> u32 {
+    (imm << 20) | ((rs1.as_num() as u32) << 15) | (funct3 << 12) | ((rd.as_num() as u32) << 7) | opcode
+}
+
+fn s_type(opcode: u32, funct3: u32, rs1: Register, rs2: Register, imm: u32) -> u32 {
+    ((imm >> 5) << 25) | ((rs2.as_num() as u32) << 20) | ((rs1.as_num() as u32) << 15) |
+        (funct3 << 12) | ((imm & 0x1F) << 7) | opcode
+}
+
+fn sb_type(opcode: u32, funct3: u32, rs1: Register, rs2: Register, imm: u32) -> u32 {
+    (((imm >> 12) & 0x1) << 31) | (((imm >> 5) & 0x3F) << 25) |
+        ((rs2.as_num() as u32) << 20) | ((rs1.as_num() as u32) << 15) | (funct3 << 12) |
+        (((imm >> 1) & 0xF) << 8) | (((imm >> 11) & 0x1) << 7) | opcode
+}
+
+fn u_type(opcode: u32, rd: Register, imm: u32) -> u32 {
+    (imm << 12) | ((rd.as_num() as u32) << 7) | opcode
+}
+
+fn uj_type(opcode: u32, rd: Register, imm: u32) -> u32 {
+    (((imm >> 20) & 0x1) << 31) | (((imm >> 1) & 0x3FF) << 21) | (((imm >> 11) & 0x1) << 20) |
+        (((imm >> 12) & 0xFF) << 12) | ((rd.as_num() as u32) << 7) | opcode
+}
+
+/// Encodes an instruction as a 32-bit RV32I machine word, checking that
+/// its immediate fits.
+pub fn encode(instruction: &Instruction) -> Result<u32, EncodeError> {
+    Ok(match *instruction {
+        Instruction::RShift { opcode, rd, rs1, shamt } => {
+            if shamt >= 32 {
+                return Err(EncodeError::ShiftOutOfRange(shamt));
+            }
+            let (funct3, funct7) = match opcode {
+                isa::RShiftOpcode::SLLI => (0b001, 0),
+                isa::RShiftOpcode::SRLI => (0b101, 0),
+                isa::RShiftOpcode::SRAI => (0b101, FUNCT7_ALTERNATE),
+            };
+            i_type(OPCODE_OP_IMM, funct3, rd, rs1, (funct7 << 5) | shamt)
+        },
+        Instruction::R { opcode, rd, rs1, rs2 } => {
+            let (funct3, funct7) = match opcode {
+                isa::ROpcode::ADD => (0b000, 0),
+                isa::ROpcode::SUB => (0b000, FUNCT7_ALTERNATE),
+                isa::ROpcode::SLL => (0b001, 0),
+                isa::ROpcode::SLT => (0b010, 0),
+                isa::ROpcode::SLTU => (0b011, 0),
+                isa::ROpcode::XOR => (0b100, 0),
+                isa::ROpcode::SRL => (0b101, 0),
+                isa::ROpcode::SRA => (0b101, FUNCT7_ALTERNATE),
+                isa::ROpcode::OR => (0b110, 0),
+                isa::ROpcode::AND => (0b111, 0),
+            };
+            r_type(OPCODE_OP, funct3, funct7, rd, rs1, rs2)
+        },
+        Instruction::I { opcode: isa::IOpcode::SCALL, .. } => OPCODE_SYSTEM,
+        Instruction::I { opcode, rd, rs1, imm } => {
+            let (major, funct3) = match opcode {
+                isa::IOpcode::JALR => (OPCODE_JALR, 0b000),
+                isa::IOpcode::LB => (OPCODE_LOAD, 0b000),
+                isa::IOpcode::LH => (OPCODE_LOAD, 0b001),
+                isa::IOpcode::LW => (OPCODE_LOAD, 0b010),
+                isa::IOpcode::LBU => (OPCODE_LOAD, 0b100),
+                isa::IOpcode::LHU => (OPCODE_LOAD, 0b101),
+                isa::IOpcode::ADDI => (OPCODE_OP_IMM, 0b000),
+                isa::IOpcode::SLTI => (OPCODE_OP_IMM, 0b010),
+                isa::IOpcode::SLTIU => (OPCODE_OP_IMM, 0b011),
+                isa::IOpcode::XORI => (OPCODE_OP_IMM, 0b100),
+                isa::IOpcode::ORI => (OPCODE_OP_IMM, 0b110),
+                isa::IOpcode::ANDI => (OPCODE_OP_IMM, 0b111),
+                isa::IOpcode::SCALL => unreachable!(),
+            };
+            i_type(major, funct3, rd, rs1, try!(check_signed(imm, 12, false)))
+        },
+        Instruction::S { opcode, rs1, rs2, imm } => {
+            let funct3 = match opcode {
+                isa::SOpcode::SB => 0b000,
+                isa::SOpcode::SH => 0b001,
+                isa::SOpcode::SW => 0b010,
+            };
+            s_type(OPCODE_STORE, funct3, rs1, rs2, try!(check_signed(imm, 12, false)))
+        },
+        Instruction::SB { opcode, rs1, rs2, imm } => {
+            let funct3 = match opcode {
+                isa::SBOpcode::BEQ => 0b000,
+                isa::SBOpcode::BNE => 0b001,
+                isa::SBOpcode::BLT => 0b100,
+                isa::SBOpcode::BGE => 0b101,
+                isa::SBOpcode::BLTU => 0b110,
+                isa::SBOpcode::BGEU => 0b111,
+            };
+            sb_type(OPCODE_BRANCH, funct3, rs1, rs2, try!(check_signed(imm, 13, true)))
+        },
+        Instruction::U { opcode, rd, imm } => {
+            if imm >= 1 << 20 {
+                return Err(EncodeError::ImmediateOutOfRange(imm as i64));
+            }
+            let major = match opcode {
+                isa::UOpcode::LUI => OPCODE_LUI,
+                isa::UOpcode::AUIPC => OPCODE_AUIPC,
+            };
+            u_type(major, rd, imm)
+        },
+        Instruction::UJ { opcode: isa::UJOpcode::JAL, rd, imm } => {
+            uj_type(OPCODE_JAL, rd, try!(check_signed(imm, 21, true)))
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use isa::{self, Instruction, Register};
+
+    use super::{decode, encode, i_type, r_type, s_type, DecodeError, EncodeError, FUNCT7_ALTERNATE, OPCODE_BRANCH,
+                OPCODE_JALR, OPCODE_LOAD, OPCODE_MISC_MEM, OPCODE_OP, OPCODE_OP_IMM, OPCODE_STORE, OPCODE_SYSTEM};
+
+    const REGISTERS: [Register; 4] = [Register::X0, Register::X1, Register::X15, Register::X31];
+
+    fn round_trip(instruction: Instruction) {
+        let word = encode(&instruction).unwrap_or_else(|err| panic!("{:?}: {}", instruction, err));
+        assert_eq!(decode(word), Ok(instruction), "{:08x}", word);
+    }
+
+    #[test]
+    fn round_trip_r() {
+        let opcodes = [isa::ROpcode::ADD, isa::ROpcode::SUB, isa::ROpcode::SLL, isa::ROpcode::SLT,
+                       isa::ROpcode::SLTU, isa::ROpcode::XOR, isa::ROpcode::SRL, isa::ROpcode::SRA,
+                       isa::ROpcode::OR, isa::ROpcode::AND];
+        for &opcode in opcodes.iter() {
+            for &rd in REGISTERS.iter() {
+                for &rs1 in REGISTERS.iter() {
+                    for &rs2 in REGISTERS.iter() {
+                        round_trip(Instruction::R { opcode: opcode, rd: rd, rs1: rs1, rs2: rs2 });
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_shift() {
+        let opcodes = [isa::RShiftOpcode::SLLI, isa::RShiftOpcode::SRLI, isa::RShiftOpcode::SRAI];
+        for &opcode in opcodes.iter() {
+            for &shamt in [0, 1, 16, 31].iter() {
+                let rd = Register::X31;
+                round_trip(Instruction::RShift { opcode: opcode, rd: rd, rs1: Register::X1, shamt: shamt });
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_i() {
+        let opcodes = [isa::IOpcode::JALR, isa::IOpcode::LB, isa::IOpcode::LH, isa::IOpcode::LW,
+                       isa::IOpcode::LBU, isa::IOpcode::LHU, isa::IOpcode::ADDI, isa::IOpcode::SLTI,
+                       isa::IOpcode::SLTIU, isa::IOpcode::XORI, isa::IOpcode::ORI, isa::IOpcode::ANDI];
+        for &opcode in opcodes.iter() {
+            for &imm in [-2048, -1, 0, 1, 2047].iter() {
+                for &rd in REGISTERS.iter() {
+                    round_trip(Instruction::I { opcode: opcode, rd: rd, rs1: Register::X31, imm: imm as u32 });
+                }
+            }
+        }
+        round_trip(Instruction::I { opcode: isa::IOpcode::SCALL, rd: Register::X0, rs1: Register::X0, imm: 0 });
+    }
+
+    #[test]
+    fn round_trip_s() {
+        for &opcode in [isa::SOpcode::SB, isa::SOpcode::SH, isa::SOpcode::SW].iter() {
+            for &imm in [-2048, -1, 0, 31, 32, 2047].iter() {
+                for &rs2 in REGISTERS.iter() {
+                    round_trip(Instruction::S { opcode: opcode, rs1: Register::X31, rs2: rs2, imm: imm as u32 });
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_sb() {
+        let opcodes = [isa::SBOpcode::BEQ, isa::SBOpcode::BNE, isa::SBOpcode::BLT, isa::SBOpcode::BGE,
+                       isa::SBOpcode::BLTU, isa::SBOpcode::BGEU];
+        for &opcode in opcodes.iter() {
+            for &imm in [-4096, -2, 0, 2, 2046, 2048, 4094].iter() {
+                for &rs1 in REGISTERS.iter() {
+                    round_trip(Instruction::SB { opcode: opcode, rs1: rs1, rs2: Register::X15, imm: imm as u32 });
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_u() {
+        for &opcode in [isa::UOpcode::LUI, isa::UOpcode::AUIPC].iter() {
+            for &imm in [0, 1, 0x80000, 0xFFFFF].iter() {
+                for &rd in REGISTERS.iter() {
+                    round_trip(Instruction::U { opcode: opcode, rd: rd, imm: imm });
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_uj() {
+        for &imm in [-(1 << 20), -2, 0, 2, 2046, 2048, 4096, (1 << 20) - 2].iter() {
+            for &rd in REGISTERS.iter() {
+                round_trip(Instruction::UJ { opcode: isa::UJOpcode::JAL, rd: rd, imm: imm as u32 });
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_range() {
+        let addi = |imm: i32| {
+            Instruction::I { opcode: isa::IOpcode::ADDI, rd: Register::X1, rs1: Register::X1, imm: imm as u32 }
+        };
+        assert_eq!(encode(&addi(2048)), Err(EncodeError::ImmediateOutOfRange(2048)));
+        assert_eq!(encode(&addi(-2049)), Err(EncodeError::ImmediateOutOfRange(-2049)));
+
+        let beq = |imm: i32| {
+            Instruction::SB { opcode: isa::SBOpcode::BEQ, rs1: Register::X1, rs2: Register::X2, imm: imm as u32 }
+        };
+        assert_eq!(encode(&beq(4096)), Err(EncodeError::ImmediateOutOfRange(4096)));
+        assert_eq!(encode(&beq(-4098)), Err(EncodeError::ImmediateOutOfRange(-4098)));
+        assert_eq!(encode(&beq(3)), Err(EncodeError::MisalignedImmediate(3)));
+
+        let jal = |imm: i32| Instruction::UJ { opcode: isa::UJOpcode::JAL, rd: Register::X1, imm: imm as u32 };
+        assert_eq!(encode(&jal(1 << 20)), Err(EncodeError::ImmediateOutOfRange(1 << 20)));
+        assert_eq!(encode(&jal(-(1 << 20) - 2)), Err(EncodeError::ImmediateOutOfRange(-(1 << 20) - 2)));
+    }
+
+    #[test]
+    fn invalid_opcodes() {
+        // Compressed, and 32-bit but not RV32I
+        for &word in [0x00000000, 0x00004501, 0x0000007F, 0x0000002F].iter() {
+            assert_eq!(decode(word), Err(DecodeError::InvalidOpcode(word)));
+        }
+    }
+
+    #[test]
+    fn invalid_functions() {
+        let (x1, x2, x3) = (Register::X1, Register::X2, Register::X3);
+        let mut words = vec![
+            // mul, from the M extension
+            r_type(OPCODE_OP, 0b000, 0b0000001, x1, x2, x3),
+            // only add and srl have alternates
+            r_type(OPCODE_OP, 0b001, FUNCT7_ALTERNATE, x1, x2, x3),
+            r_type(OPCODE_OP, 0b111, FUNCT7_ALTERNATE, x1, x2, x3),
+            // slli and srli/srai with a bad funct7
+            i_type(OPCODE_OP_IMM, 0b001, x1, x2, FUNCT7_ALTERNATE << 5),
+            i_type(OPCODE_OP_IMM, 0b101, x1, x2, 0b0000001 << 5),
+            i_type(OPCODE_OP_IMM, 0b101, x1, x2, 0b1000000 << 5),
+            i_type(OPCODE_JALR, 0b001, x1, x2, 0),
+            i_type(OPCODE_BRANCH, 0b010, x1, x2, 0),
+            i_type(OPCODE_BRANCH, 0b011, x1, x2, 0),
+        ];
+        // ld, lwu and two unused loads
+        for &funct3 in [0b011, 0b110, 0b111].iter() {
+            words.push(i_type(OPCODE_LOAD, funct3, x1, x2, 0));
+        }
+        // sd and four unused stores
+        for &funct3 in [0b011, 0b100, 0b101, 0b110, 0b111].iter() {
+            words.push(s_type(OPCODE_STORE, funct3, x1, x2, 0));
+        }
+
+        for &word in words.iter() {
+            assert_eq!(decode(word), Err(DecodeError::InvalidFunction(word)));
+            // The message names the parts that didn't make sense
+            assert!(format!("{}", decode(word).unwrap_err()).contains("invalid function"));
+        }
+    }
+
+    #[test]
+    fn unsupported_and_reserved() {
+        // fence, sbreak and csrrw
+        for &word in [i_type(OPCODE_MISC_MEM, 0, Register::X0, Register::X0, 0),
+                      i_type(OPCODE_SYSTEM, 0, Register::X0, Register::X0, 1),
+                      i_type(OPCODE_SYSTEM, 0b001, Register::X1, Register::X2, 0x300)].iter() {
+            assert_eq!(decode(word), Err(DecodeError::Unsupported(word)));
+        }
+
+        let word = i_type(OPCODE_SYSTEM, 0, Register::X1, Register::X0, 0);
+        assert_eq!(decode(word), Err(DecodeError::ReservedBits(word)));
+        let word = i_type(OPCODE_SYSTEM, 0, Register::X0, Register::X0, 2);
+        assert_eq!(decode(word), Err(DecodeError::InvalidFunction(word)));
+    }
+}
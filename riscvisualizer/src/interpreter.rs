@@ -1,26 +1,835 @@
-use isa;
+use std::any::Any;
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
 
-struct Interpreter {
+use assembler;
+use binary;
+use isa::{self, Instruction, Register};
+use memory::{Cache, CacheConfig, CacheEvent, CacheStats, HierarchyConfig, Memory, MemoryAccess, MemoryError,
+             MemoryInterface, ReplacementPolicy};
+use types::{Address, IsaType, SignedWord, Word};
+
+/// Why an instruction couldn't complete. The instruction has no effect
+/// and the PC stays on it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Trap {
+    IllegalInstruction(binary::DecodeError),
+    // target of a jump or taken branch that isn't word-aligned
+    MisalignedJump(Address),
+    // load or store address that isn't a multiple of its size
+    MisalignedAccess(Address),
+    MemoryError(Address, MemoryError),
+    // SCALL that isn't one of the calls below; the program wants the
+    // environment's attention
+    EnvironmentCall,
+}
+
+// Environment calls the interpreter answers itself, chosen by a0. Both
+// take an address in a1 and act on the data memory's caches, and the
+// program waits for any line fill or write-back they cause.
+pub const CALL_PREFETCH: u32 = 1;
+pub const CALL_INVALIDATE: u32 = 2;
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Trap::IllegalInstruction(ref err) => write!(f, "illegal instruction: {}", err),
+            Trap::MisalignedJump(target) => write!(f, "jump to misaligned address {:08x}", target),
+            Trap::MisalignedAccess(address) => write!(f, "misaligned memory access at {:08x}", address),
+            Trap::MemoryError(address, MemoryError::InvalidAddress) =>
+                write!(f, "invalid memory address {:08x}", address),
+            Trap::EnvironmentCall => write!(f, "environment call"),
+        }
+    }
+}
+
+pub struct Interpreter {
     memory_words: usize,
-    cache_lines: usize,
-    cache_line_words: usize,
-    cache_stall_cycles: usize,
+    // for loads and stores
+    memory: Box<MemoryInterface>,
+    // for instruction fetches, if they don't go through `memory`
+    instruction_memory: Option<Box<MemoryInterface>>,
+    // every cache in `memory` and `instruction_memory`, closest to the
+    // processor first, for inspecting them
+    caches: Vec<Rc<RefCell<Cache>>>,
+    registers: [Word; 32],
+    pc: Address,
+    // total, including memory stalls
+    cycles: usize,
+    history: Vec<Action>,
+    keep_history: bool,
 }
 
-struct Action {
+/// Everything one step did, with enough of the old state to undo it.
+pub struct Action {
+    pub instruction: Instruction,
     // pc: before after
-    // read registers
-    // written register(s)
-    // read memory
-    // written memory
+    pub pc: (Address, Address),
+    // (register, value) for each source register
+    pub registers_read: Vec<(Register, Word)>,
+    // (register, old value, new value); writes to x0 aren't recorded
+    pub register_written: Option<(Register, Word, Word)>,
+    // (address, value loaded)
+    pub memory_read: Option<(Address, Word)>,
+    // (word-aligned address, old word, new word)
+    pub memory_written: Option<(Address, Word, Word)>,
+    pub cycles: usize,
+    // (call, address) for a prefetch or invalidate environment call
+    pub cache_call: Option<(u32, Address)>,
+    // what the caches did, as (index into `caches()`, event), in order
+    // for each cache
+    pub cache_events: Vec<(usize, CacheEvent)>,
+    // memory's bookkeeping from before the step
+    memory_snapshot: Option<Box<Any>>,
+    instruction_snapshot: Option<Box<Any>>,
 }
 
+/// Cycles for each access to main memory.
+pub const MEMORY_LATENCY: usize = 10;
+
 impl Interpreter {
-    fn step(&mut self) {
+    /// Creates an interpreter with `memory_words` words of main memory
+    /// holding the program, ready to run it from address 0.
+    pub fn new(memory_words: usize, image: &assembler::Image) -> Result<Interpreter, MemoryError> {
+        let mut memory = Memory::new(memory_words, MEMORY_LATENCY);
+        try!(memory.load_image(image));
+        Ok(Interpreter::with_memory(Box::new(memory), memory_words))
+    }
+
+    /// Like `new`, but with a cache between the interpreter and main
+    /// memory.
+    pub fn with_cache(memory_words: usize, config: CacheConfig, image: &assembler::Image)
+                      -> Result<Interpreter, MemoryError> {
+        let mut memory = Memory::new(memory_words, MEMORY_LATENCY);
+        try!(memory.load_image(image));
+        let cache = Rc::new(RefCell::new(Cache::new("L1", Box::new(memory), config)));
+
+        let mut interpreter = Interpreter::with_memory(Box::new(cache.clone()), memory_words);
+        interpreter.caches.push(cache);
+        Ok(interpreter)
+    }
+
+    /// Like `new`, but with a hierarchy of caches. Instruction fetches
+    /// go through the L1 instruction cache and loads and stores
+    /// through the L1 data cache.
+    pub fn with_hierarchy(memory_words: usize, config: HierarchyConfig, image: &assembler::Image)
+                          -> Result<Interpreter, MemoryError> {
+        let mut memory = Memory::new(memory_words, MEMORY_LATENCY);
+        try!(memory.load_image(image));
+
+        let mut outer: Vec<Rc<RefCell<Cache>>> = vec![];
+        let l2_next: Box<MemoryInterface> = match config.l3 {
+            Some(l3_config) => {
+                let l3 = Rc::new(RefCell::new(Cache::new("L3", Box::new(memory), l3_config)));
+                outer.push(l3.clone());
+                Box::new(l3)
+            }
+            None => Box::new(memory),
+        };
+        let l2 = Rc::new(RefCell::new(Cache::new("L2", l2_next, config.l2)));
+        outer.insert(0, l2.clone());
+        let l1i = Rc::new(RefCell::new(Cache::new("L1I", Box::new(l2.clone()), config.l1i)));
+        let l1d = Rc::new(RefCell::new(Cache::new("L1D", Box::new(l2), config.l1d)));
+
+        let mut interpreter = Interpreter::with_memory(Box::new(l1d.clone()), memory_words);
+        interpreter.instruction_memory = Some(Box::new(l1i.clone()));
+        interpreter.caches = vec![l1i, l1d];
+        interpreter.caches.extend(outer);
+        Ok(interpreter)
+    }
+
+    /// Creates an interpreter that runs directly against the given
+    /// memory, with no cache in front of it. Useful for memory with
+    /// devices mapped into it.
+    pub fn with_memory(memory: Box<MemoryInterface>, memory_words: usize) -> Interpreter {
+        Interpreter {
+            memory_words: memory_words,
+            memory: memory,
+            instruction_memory: None,
+            caches: vec![],
+            registers: [Word(0); 32],
+            pc: Word(0),
+            cycles: 0,
+            history: vec![],
+            keep_history: true,
+        }
+    }
+
+    pub fn memory_words(&self) -> usize {
+        self.memory_words
+    }
+
+    pub fn memory(&mut self) -> &mut MemoryInterface {
+        &mut *self.memory
+    }
+
+    /// Every cache, closest to the processor first.
+    pub fn caches(&self) -> &[Rc<RefCell<Cache>>] {
+        &self.caches
+    }
+
+    pub fn pc(&self) -> Address {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: Address) {
+        self.pc = pc;
+    }
+
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    pub fn register(&self, register: Register) -> Word {
+        self.registers[register.as_num()]
+    }
+
+    /// Writes to x0 are ignored.
+    pub fn set_register(&mut self, register: Register, value: Word) {
+        if register != Register::X0 {
+            self.registers[register.as_num()] = value;
+        }
+    }
+
+    /// Every step taken since the start, oldest first.
+    pub fn history(&self) -> &[Action] {
+        &self.history
+    }
+
+    /// Whether to record steps so they can be undone. Turning it off
+    /// forgets the history so far and stops snapshotting the caches
+    /// every step; long-running programs that never step back shouldn't
+    /// keep it. Without history, an instruction that traps still counts
+    /// its fetch in the cache statistics.
+    pub fn set_keep_history(&mut self, keep_history: bool) {
+        self.keep_history = keep_history;
+        if !keep_history {
+            self.forget_history();
+        }
+    }
+
+    /// Forgets the steps taken so far, so they can't be undone.
+    pub fn forget_history(&mut self) {
+        self.history.clear();
+    }
+
+    fn check_jump(&self, target: Address) -> Result<Address, Trap> {
+        if target.0 % 4 != 0 {
+            Err(Trap::MisalignedJump(target))
+        }
+        else {
+            Ok(target)
+        }
+    }
+
+    fn check_alignment(&self, address: Address, size: u32) -> Result<(), Trap> {
+        if address.0 % size != 0 {
+            Err(Trap::MisalignedAccess(address))
+        }
+        else {
+            Ok(())
+        }
+    }
+
+    /// Executes the instruction at the PC.
+    pub fn step(&mut self) -> Result<(), Trap> {
+        let (snapshot, instruction_snapshot) = if self.keep_history {
+            (self.memory.snapshot(), self.instruction_memory.as_ref().and_then(|memory| memory.snapshot()))
+        }
+        else {
+            (None, None)
+        };
+        let result = self.execute();
+        let mut cache_events = vec![];
+        for (i, cache) in self.caches.iter().enumerate() {
+            cache_events.extend(cache.borrow_mut().take_events().into_iter().map(|event| (i, event)));
+        }
+
+        match result {
+            Ok(mut action) => {
+                action.cache_events = cache_events;
+                self.pc = action.pc.1;
+                self.cycles += action.cycles;
+                if self.keep_history {
+                    action.memory_snapshot = snapshot;
+                    action.instruction_snapshot = instruction_snapshot;
+                    self.history.push(action);
+                }
+                Ok(())
+            }
+            Err(trap) => {
+                // The fetch may have touched a cache even though the
+                // instruction had no effect
+                self.restore(snapshot, instruction_snapshot);
+                Err(trap)
+            }
+        }
+    }
+
+    /// Steps until the program traps or `max_steps` steps have run.
+    /// Returns the trap, if there was one.
+    pub fn run(&mut self, max_steps: usize) -> Option<Trap> {
+        for _ in 0..max_steps {
+            if let Err(trap) = self.step() {
+                return Some(trap);
+            }
+        }
+        None
+    }
+
+    /// Undoes the most recent step. Returns false if there's nothing
+    /// left to undo.
+    pub fn step_back(&mut self) -> bool {
+        let action = match self.history.pop() {
+            Some(action) => action,
+            None => return false,
+        };
 
+        if let Some((address, old, _)) = action.memory_written {
+            self.memory.poke_word(address, old).expect("undoing a write that succeeded");
+        }
+        self.restore(action.memory_snapshot, action.instruction_snapshot);
+        if let Some((register, old, _)) = action.register_written {
+            self.registers[register.as_num()] = old;
+        }
+        self.pc = action.pc.0;
+        self.cycles -= action.cycles;
+        true
     }
 
-    fn step_back(&mut self) {
+    fn restore(&mut self, snapshot: Option<Box<Any>>, instruction_snapshot: Option<Box<Any>>) {
+        if let Some(snapshot) = snapshot {
+            self.memory.restore(snapshot);
+        }
+        if let (Some(memory), Some(snapshot)) = (self.instruction_memory.as_mut(), instruction_snapshot) {
+            memory.restore(snapshot);
+        }
+    }
+
+    /// Performs the instruction at the PC, except for updating the PC
+    /// and cycle count. If it traps, registers and memory are untouched.
+    fn execute(&mut self) -> Result<Action, Trap> {
+        let pc = self.pc;
+        let MemoryAccess(word, fetch_cycles) = {
+            let fetch = match self.instruction_memory {
+                Some(ref mut memory) => memory,
+                None => &mut self.memory,
+            };
+            try!(fetch.read_word(pc).map_err(|err| Trap::MemoryError(pc, err)))
+        };
+        let instruction = try!(binary::decode(word.0).map_err(Trap::IllegalInstruction));
+
+        let mut action = Action {
+            instruction: instruction,
+            pc: (pc, pc.wrapping_add(Word(4))),
+            registers_read: vec![],
+            register_written: None,
+            memory_read: None,
+            memory_written: None,
+            cycles: 1 + fetch_cycles,
+            cache_call: None,
+            cache_events: vec![],
+            memory_snapshot: None,
+            instruction_snapshot: None,
+        };
+        // (destination, value)
+        let mut result = None;
+
+        match instruction {
+            Instruction::R { opcode, rd, rs1, rs2 } => {
+                let a = self.read_register(&mut action, rs1);
+                let b = self.read_register(&mut action, rs2);
+                let shift = b.0 & 0x1F;
+                let value = match opcode {
+                    isa::ROpcode::ADD => a.wrapping_add(b),
+                    isa::ROpcode::SUB => a.wrapping_sub(b),
+                    isa::ROpcode::SLL => a << shift,
+                    isa::ROpcode::SLT => Word((a.as_signed() < b.as_signed()) as u32),
+                    isa::ROpcode::SLTU => Word((a < b) as u32),
+                    isa::ROpcode::XOR => a ^ b,
+                    isa::ROpcode::SRL => a >> shift,
+                    isa::ROpcode::SRA => SignedWord(a.as_signed().0 >> shift).as_word(),
+                    isa::ROpcode::OR => a | b,
+                    isa::ROpcode::AND => a & b,
+                };
+                result = Some((rd, value));
+            }
+            Instruction::RShift { opcode, rd, rs1, shamt } => {
+                let a = self.read_register(&mut action, rs1);
+                let value = match opcode {
+                    isa::RShiftOpcode::SLLI => a << shamt,
+                    isa::RShiftOpcode::SRLI => a >> shamt,
+                    isa::RShiftOpcode::SRAI => SignedWord(a.as_signed().0 >> shamt).as_word(),
+                };
+                result = Some((rd, value));
+            }
+            Instruction::I { opcode: isa::IOpcode::SCALL, .. } => {
+                let call = self.read_register(&mut action, Register::X10).0;
+                if call != CALL_PREFETCH && call != CALL_INVALIDATE {
+                    return Err(Trap::EnvironmentCall);
+                }
+                let address = self.read_register(&mut action, Register::X11);
+                action.cycles += if call == CALL_PREFETCH {
+                    self.memory.prefetch(address)
+                }
+                else {
+                    self.memory.invalidate(address)
+                };
+                action.cache_call = Some((call, address));
+            }
+            Instruction::I { opcode: isa::IOpcode::JALR, rd, rs1, imm } => {
+                let target = self.read_register(&mut action, rs1).wrapping_add(Word(imm)) & !1;
+                action.pc.1 = try!(self.check_jump(target));
+                result = Some((rd, pc.wrapping_add(Word(4))));
+            }
+            Instruction::I { opcode, rd, rs1, imm } => {
+                let a = self.read_register(&mut action, rs1);
+                let imm = Word(imm);
+                let address = a.wrapping_add(imm);
+                let value = match opcode {
+                    isa::IOpcode::ADDI => address,
+                    isa::IOpcode::SLTI => Word((a.as_signed() < imm.as_signed()) as u32),
+                    isa::IOpcode::SLTIU => Word((a < imm) as u32),
+                    isa::IOpcode::XORI => a ^ imm,
+                    isa::IOpcode::ORI => a | imm,
+                    isa::IOpcode::ANDI => a & imm,
+                    isa::IOpcode::LB | isa::IOpcode::LBU => {
+                        let MemoryAccess(byte, load_cycles) =
+                            try!(self.memory.read_byte(address).map_err(|err| Trap::MemoryError(address, err)));
+                        action.cycles += load_cycles;
+                        if opcode == isa::IOpcode::LB { byte.as_signed_word().as_word() } else { byte.as_word() }
+                    }
+                    isa::IOpcode::LH | isa::IOpcode::LHU => {
+                        try!(self.check_alignment(address, 2));
+                        let MemoryAccess(half, load_cycles) =
+                            try!(self.memory.read_halfword(address).map_err(|err| Trap::MemoryError(address, err)));
+                        action.cycles += load_cycles;
+                        if opcode == isa::IOpcode::LH { half.as_signed_word().as_word() } else { half.as_word() }
+                    }
+                    isa::IOpcode::LW => {
+                        try!(self.check_alignment(address, 4));
+                        let MemoryAccess(word, load_cycles) =
+                            try!(self.memory.read_word(address).map_err(|err| Trap::MemoryError(address, err)));
+                        action.cycles += load_cycles;
+                        word
+                    }
+                    isa::IOpcode::JALR | isa::IOpcode::SCALL => unreachable!(),
+                };
+                match opcode {
+                    isa::IOpcode::LB | isa::IOpcode::LBU | isa::IOpcode::LH |
+                    isa::IOpcode::LHU | isa::IOpcode::LW => action.memory_read = Some((address, value)),
+                    _ => {}
+                }
+                result = Some((rd, value));
+            }
+            Instruction::S { opcode, rs1, rs2, imm } => {
+                let address = self.read_register(&mut action, rs1).wrapping_add(Word(imm));
+                let value = self.read_register(&mut action, rs2);
+                let word_address = address & !3;
+                let old = try!(self.memory.peek_word(word_address)
+                              .map_err(|err| Trap::MemoryError(address, err)));
+                let written = match opcode {
+                    isa::SOpcode::SB => self.memory.write_byte(address, value.as_byte()),
+                    isa::SOpcode::SH => {
+                        try!(self.check_alignment(address, 2));
+                        self.memory.write_halfword(address, value.as_half_word())
+                    }
+                    isa::SOpcode::SW => {
+                        try!(self.check_alignment(address, 4));
+                        self.memory.write_word(address, value)
+                    }
+                };
+                let MemoryAccess((), store_cycles) = try!(written.map_err(|err| Trap::MemoryError(address, err)));
+                action.cycles += store_cycles;
+                let new = try!(self.memory.peek_word(word_address)
+                              .map_err(|err| Trap::MemoryError(address, err)));
+                action.memory_written = Some((word_address, old, new));
+            }
+            Instruction::SB { opcode, rs1, rs2, imm } => {
+                let a = self.read_register(&mut action, rs1);
+                let b = self.read_register(&mut action, rs2);
+                let taken = match opcode {
+                    isa::SBOpcode::BEQ => a == b,
+                    isa::SBOpcode::BNE => a != b,
+                    isa::SBOpcode::BLT => a.as_signed() < b.as_signed(),
+                    isa::SBOpcode::BGE => a.as_signed() >= b.as_signed(),
+                    isa::SBOpcode::BLTU => a < b,
+                    isa::SBOpcode::BGEU => a >= b,
+                };
+                if taken {
+                    action.pc.1 = try!(self.check_jump(pc.wrapping_add(Word(imm))));
+                }
+            }
+            Instruction::U { opcode, rd, imm } => {
+                let upper = Word(imm << 12);
+                let value = match opcode {
+                    isa::UOpcode::LUI => upper,
+                    isa::UOpcode::AUIPC => pc.wrapping_add(upper),
+                };
+                result = Some((rd, value));
+            }
+            Instruction::UJ { opcode: isa::UJOpcode::JAL, rd, imm } => {
+                action.pc.1 = try!(self.check_jump(pc.wrapping_add(Word(imm))));
+                result = Some((rd, pc.wrapping_add(Word(4))));
+            }
+        }
+
+        if let Some((rd, value)) = result {
+            if rd != Register::X0 {
+                action.register_written = Some((rd, self.register(rd), value));
+                self.set_register(rd, value);
+            }
+        }
+
+        Ok(action)
+    }
+
+    fn read_register(&self, action: &mut Action, register: Register) -> Word {
+        let value = self.register(register);
+        action.registers_read.push((register, value));
+        value
+    }
+}
+
+/// Runs the program once for each replacement policy, with an otherwise
+/// identical cache, and returns each policy's statistics and the cycles
+/// the program took.
+pub fn compare_policies(memory_words: usize, config: CacheConfig, image: &assembler::Image,
+                        policies: &[ReplacementPolicy], max_steps: usize)
+                        -> Result<Vec<(ReplacementPolicy, CacheStats, usize)>, MemoryError> {
+    let mut results = vec![];
+    for &policy in policies.iter() {
+        let config = CacheConfig { policy: policy, ..config };
+        let mut interpreter = try!(Interpreter::with_cache(memory_words, config, image));
+        interpreter.set_keep_history(false);
+        interpreter.run(max_steps);
+        let stats = interpreter.caches()[0].borrow().stats();
+        results.push((policy, stats, interpreter.cycles()));
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use assembler;
+    use isa::Register;
+    use memory::{CacheConfig, CacheLine, CacheStats, HierarchyConfig, Prefetcher, ReplacementPolicy,
+                 WriteMissPolicy, WritePolicy, CACHE_HIT_CYCLES};
+    use types::Word;
+
+    use super::{Interpreter, Trap, CALL_INVALIDATE, CALL_PREFETCH, MEMORY_LATENCY};
+
+    const MEMORY_WORDS: usize = 1024;
+
+    fn load(source: &str) -> Interpreter {
+        let image = assembler::assemble(source).unwrap();
+        Interpreter::new(MEMORY_WORDS, &image).unwrap()
+    }
+
+    /// Runs `source` until it makes an environment call.
+    fn run(source: &str) -> Interpreter {
+        let mut interpreter = load(source);
+        assert_eq!(interpreter.run(1000), Some(Trap::EnvironmentCall));
+        interpreter
+    }
+
+    fn register(interpreter: &Interpreter, number: u32) -> u32 {
+        interpreter.register(Register::from_num(number).unwrap()).0
+    }
+
+    #[test]
+    fn x0_is_zero() {
+        let interpreter = run("
+            addi x0, x0, 5
+            lui x0, 1
+            jal x0, next
+        next:
+            add x1, x0, x0
+            scall");
+        assert_eq!(register(&interpreter, 0), 0);
+        assert_eq!(register(&interpreter, 1), 0);
+        // Writes to x0 aren't recorded, so there's nothing to undo
+        assert!(interpreter.history().iter().all(|action| {
+            action.register_written.map_or(true, |(register, _, _)| register != Register::X0)
+        }));
+    }
+
+    #[test]
+    fn signed_and_unsigned_compares() {
+        let interpreter = run("
+            li x1, -1
+            li x2, 1
+            slt x3, x1, x2
+            sltu x4, x1, x2
+            slti x5, x1, 0
+            sltiu x6, x2, -1
+            li x7, 0
+            blt x1, x2, signed
+            addi x7, x7, 1
+        signed:
+            bltu x1, x2, unsigned
+            addi x7, x7, 2
+        unsigned:
+            scall");
+        assert_eq!(register(&interpreter, 3), 1);
+        assert_eq!(register(&interpreter, 4), 0);
+        assert_eq!(register(&interpreter, 5), 1);
+        // The immediate is sign-extended, then compared unsigned
+        assert_eq!(register(&interpreter, 6), 1);
+        // blt was taken, bltu wasn't
+        assert_eq!(register(&interpreter, 7), 2);
+    }
+
+    #[test]
+    fn arithmetic_shifts() {
+        let interpreter = run("
+            li x1, -16
+            li x2, 2
+            sra x3, x1, x2
+            srai x4, x1, 2
+            srl x5, x1, x2
+            srli x6, x1, 2
+            li x7, 34
+            sra x8, x1, x7
+            scall");
+        assert_eq!(register(&interpreter, 3), -4i32 as u32);
+        assert_eq!(register(&interpreter, 4), -4i32 as u32);
+        assert_eq!(register(&interpreter, 5), 0xFFFFFFF0 >> 2);
+        assert_eq!(register(&interpreter, 6), 0xFFFFFFF0 >> 2);
+        // Only the low 5 bits of the shift amount count
+        assert_eq!(register(&interpreter, 8), -4i32 as u32);
+    }
+
+    #[test]
+    fn sign_extending_loads() {
+        let interpreter = run("
+            la x1, data
+            lb x2, 0(x1)
+            lbu x3, 0(x1)
+            lb x4, 1(x1)
+            lh x5, 2(x1)
+            lhu x6, 2(x1)
+            scall
+            .data
+        data:
+            .byte 0x80, 0x7F
+            .half 0x8001");
+        assert_eq!(register(&interpreter, 2), 0xFFFFFF80);
+        assert_eq!(register(&interpreter, 3), 0x80);
+        assert_eq!(register(&interpreter, 4), 0x7F);
+        assert_eq!(register(&interpreter, 5), 0xFFFF8001);
+        assert_eq!(register(&interpreter, 6), 0x8001);
+    }
+
+    #[test]
+    fn jumps_link() {
+        let interpreter = run("
+            jal x1, function
+            li x3, 1
+            scall
+        function:
+            la x5, back
+            jalr x2, x5, 0
+            li x4, 1
+        back:
+            jalr x0, x1, 0");
+        assert_eq!(register(&interpreter, 1), 4);
+        assert_eq!(register(&interpreter, 2), 24);
+        assert_eq!(register(&interpreter, 3), 1);
+        assert_eq!(register(&interpreter, 4), 0);
+    }
+
+    #[test]
+    fn misaligned_jump_traps() {
+        let mut interpreter = load("
+            li x1, 1
+            jalr x2, x1, 1");
+        interpreter.step().unwrap();
+        let cycles = interpreter.cycles();
+        assert_eq!(interpreter.step(), Err(Trap::MisalignedJump(Word(2))));
+        // The jump had no effect
+        assert_eq!(interpreter.pc(), Word(4));
+        assert_eq!(register(&interpreter, 2), 0);
+        assert_eq!(interpreter.cycles(), cycles);
+    }
+
+    // Everything a step can change
+    #[derive(Debug, PartialEq)]
+    struct State {
+        registers: Vec<u32>,
+        memory: Vec<u32>,
+        pc: Word,
+        cycles: usize,
+        stats: Vec<CacheStats>,
+        sets: Vec<Vec<Vec<CacheLine>>>,
+    }
+
+    fn state(interpreter: &mut Interpreter) -> State {
+        let memory = (0..MEMORY_WORDS).map(|i| {
+            interpreter.memory().peek_word(Word(4 * i as u32)).unwrap().0
+        }).collect();
+        State {
+            registers: (0..32).map(|i| register(interpreter, i)).collect(),
+            memory: memory,
+            pc: interpreter.pc(),
+            cycles: interpreter.cycles(),
+            stats: interpreter.caches().iter().map(|cache| cache.borrow().stats()).collect(),
+            sets: interpreter.caches().iter().map(|cache| cache.borrow().sets().to_vec()).collect(),
+        }
+    }
+
+    #[test]
+    fn step_back_undoes_steps() {
+        let image = assembler::assemble("
+            la x1, data
+            li x2, 0
+            li x3, 40
+        loop:
+            lw x4, 0(x1)
+            add x4, x4, x2
+            sw x4, 0(x1)
+            sb x2, 65(x1)
+            addi x1, x1, 12
+            addi x2, x2, 1
+            blt x2, x3, loop
+            scall
+            .data
+        data:
+            .word 1").unwrap();
+        // Small enough that the loop evicts dirty lines at every level
+        let l1 = CacheConfig {
+            lines: 4,
+            line_words: 2,
+            ways: 2,
+            stall_cycles: 2,
+            policy: ReplacementPolicy::Lru,
+            write_policy: WritePolicy::WriteBack,
+            write_miss_policy: WriteMissPolicy::WriteAllocate,
+            prefetcher: Prefetcher::NextLine,
+        };
+        let hierarchy = HierarchyConfig {
+            l1i: l1,
+            l1d: l1,
+            l2: CacheConfig { lines: 8, stall_cycles: 4, prefetcher: Prefetcher::NoPrefetcher, ..l1 },
+            l3: None,
+        };
+        let mut interpreter = Interpreter::with_hierarchy(MEMORY_WORDS, hierarchy, &image).unwrap();
+
+        let mut states = vec![];
+        loop {
+            states.push(state(&mut interpreter));
+            match interpreter.step() {
+                Ok(()) => (),
+                Err(trap) => {
+                    assert_eq!(trap, Trap::EnvironmentCall);
+                    break;
+                }
+            }
+        }
+        // The trap had no effect
+        assert_eq!(state(&mut interpreter), *states.last().unwrap());
+        assert!(interpreter.caches().iter().any(|cache| cache.borrow().stats().writebacks > 0));
+
+        while let Some(expected) = states.pop() {
+            assert_eq!(state(&mut interpreter), expected, "{} steps in", states.len());
+            assert_eq!(interpreter.step_back(), !states.is_empty());
+        }
+    }
+
+    #[test]
+    fn no_history_without_keep_history() {
+        let mut interpreter = load("
+            li x1, 1
+            scall");
+        interpreter.set_keep_history(false);
+        interpreter.step().unwrap();
+        assert!(interpreter.history().is_empty());
+        assert!(!interpreter.step_back());
+        assert_eq!(register(&interpreter, 1), 1);
+    }
+
+    #[test]
+    fn cache_calls() {
+        let image = assembler::assemble("
+            la a1, data
+            li a0, 1
+            scall
+            lw t0, 0(a1)
+            li a0, 2
+            scall
+            lw t0, 0(a1)
+            li a0, 0
+            scall
+            .data
+        data:
+            .word 7").unwrap();
+        let l1 = CacheConfig {
+            lines: 4,
+            line_words: 2,
+            ways: 2,
+            stall_cycles: 2,
+            policy: ReplacementPolicy::Lru,
+            write_policy: WritePolicy::WriteBack,
+            write_miss_policy: WriteMissPolicy::WriteAllocate,
+            prefetcher: Prefetcher::NoPrefetcher,
+        };
+        let hierarchy = HierarchyConfig { l1i: l1, l1d: l1, l2: CacheConfig { lines: 8, ..l1 }, l3: None };
+        let mut interpreter = Interpreter::with_hierarchy(MEMORY_WORDS, hierarchy, &image).unwrap();
+        let start = state(&mut interpreter);
+
+        assert_eq!(interpreter.run(100), Some(Trap::EnvironmentCall));
+        assert_eq!(register(&interpreter, 5), 7);
+        // The prefetch turned the first load into a hit, and the
+        // invalidate turned the second into a miss
+        let stats = interpreter.caches()[1].borrow().stats();
+        assert_eq!((stats.prefetches, stats.useful_prefetches), (1, 1));
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+
+        while interpreter.step_back() {}
+        assert_eq!(state(&mut interpreter), start);
+    }
+
+    #[test]
+    fn cache_calls_take_cycles() {
+        let image = assembler::assemble("
+            la a1, data
+            li a0, 1
+            scall
+            sw a0, 0(a1)
+            li a0, 2
+            scall
+            li a0, 0
+            scall
+            .data
+        data:
+            .word 7").unwrap();
+        let config = CacheConfig {
+            lines: 4,
+            line_words: 2,
+            ways: 2,
+            stall_cycles: 2,
+            policy: ReplacementPolicy::Lru,
+            write_policy: WritePolicy::WriteBack,
+            write_miss_policy: WriteMissPolicy::WriteAllocate,
+            prefetcher: Prefetcher::NoPrefetcher,
+        };
+        let mut interpreter = Interpreter::with_cache(MEMORY_WORDS, config, &image).unwrap();
+        assert_eq!(interpreter.run(100), Some(Trap::EnvironmentCall));
+        let data = Word(register(&interpreter, 11));
+        let fill = config.stall_cycles + MEMORY_LATENCY;
+
+        let history = interpreter.history();
+        let calls: Vec<_> = history.iter().filter_map(|action| action.cache_call).collect();
+        assert_eq!(calls, vec![(CALL_PREFETCH, data), (CALL_INVALIDATE, data)]);
 
+        // The scall's fetch hits, then the prefetch fills the data line
+        let prefetch = &history[3];
+        assert_eq!(prefetch.cycles, 1 + CACHE_HIT_CYCLES + fill);
+        // The scall starts a new line, then the invalidate writes back
+        // the line the store dirtied
+        let invalidate = &history[6];
+        assert_eq!(invalidate.cycles, 1 + CACHE_HIT_CYCLES + fill + config.stall_cycles + MEMORY_LATENCY);
+        assert_eq!(interpreter.cycles(), history.iter().map(|action| action.cycles).sum());
     }
 }
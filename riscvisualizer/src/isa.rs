@@ -1,4 +1,7 @@
-#[derive(Debug, PartialEq)]
+use std::fmt;
+use std::str;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Register {
     X0 = 0,
     X1 = 1,
@@ -39,52 +42,146 @@ impl Register {
         self as usize
     }
 
-    pub fn from_num(num: u32) -> Register {
+    pub fn from_num(num: u32) -> Result<Register, RegisterError> {
         match num {
-            0 => Register::X0,
-            1 => Register::X1,
-            2 => Register::X2,
-            3 => Register::X3,
-            4 => Register::X4,
-            5 => Register::X5,
-            6 => Register::X6,
-            7 => Register::X7,
-            8 => Register::X8,
-            9 => Register::X9,
-            10 => Register::X10,
-            11 => Register::X11,
-            12 => Register::X12,
-            13 => Register::X13,
-            14 => Register::X14,
-            15 => Register::X15,
-            16 => Register::X16,
-            17 => Register::X17,
-            18 => Register::X18,
-            19 => Register::X19,
-            20 => Register::X20,
-            21 => Register::X21,
-            22 => Register::X22,
-            23 => Register::X23,
-            24 => Register::X24,
-            25 => Register::X25,
-            26 => Register::X26,
-            27 => Register::X27,
-            28 => Register::X28,
-            29 => Register::X29,
-            30 => Register::X30,
-            31 => Register::X31,
-            _ => panic!("Invalid register number: {}", num),
+            0 => Ok(Register::X0),
+            1 => Ok(Register::X1),
+            2 => Ok(Register::X2),
+            3 => Ok(Register::X3),
+            4 => Ok(Register::X4),
+            5 => Ok(Register::X5),
+            6 => Ok(Register::X6),
+            7 => Ok(Register::X7),
+            8 => Ok(Register::X8),
+            9 => Ok(Register::X9),
+            10 => Ok(Register::X10),
+            11 => Ok(Register::X11),
+            12 => Ok(Register::X12),
+            13 => Ok(Register::X13),
+            14 => Ok(Register::X14),
+            15 => Ok(Register::X15),
+            16 => Ok(Register::X16),
+            17 => Ok(Register::X17),
+            18 => Ok(Register::X18),
+            19 => Ok(Register::X19),
+            20 => Ok(Register::X20),
+            21 => Ok(Register::X21),
+            22 => Ok(Register::X22),
+            23 => Ok(Register::X23),
+            24 => Ok(Register::X24),
+            25 => Ok(Register::X25),
+            26 => Ok(Register::X26),
+            27 => Ok(Register::X27),
+            28 => Ok(Register::X28),
+            29 => Ok(Register::X29),
+            30 => Ok(Register::X30),
+            31 => Ok(Register::X31),
+            _ => Err(RegisterError::InvalidNumber(num)),
+        }
+    }
+
+    /// The register's name in the standard calling convention.
+    pub fn abi_name(self) -> &'static str {
+        ABI_NAMES[self.as_num()]
+    }
+
+    /// Parses either an x-name (`x0` to `x31`) or an ABI name (`zero`,
+    /// `ra`, `sp`, `gp`, `tp`, `t0`-`t6`, `s0`-`s11` or `fp`, `a0`-`a7`).
+    pub fn from_name(name: &str) -> Result<Register, RegisterError> {
+        let invalid = || RegisterError::InvalidName(name.to_owned());
+
+        if name == "fp" {
+            return Ok(Register::X8);
+        }
+        if let Some(num) = ABI_NAMES.iter().position(|abi_name| *abi_name == name) {
+            return Register::from_num(num as u32);
+        }
+        // Only plain decimal numbers, so "x+1" or "x01" aren't registers
+        if name.starts_with('x') && name.len() > 1 && name[1..].chars().all(|c| c.is_digit(10)) &&
+            (name == "x0" || !name[1..].starts_with('0')) {
+            return name[1..].parse().map_err(|_| invalid()).and_then(|num| {
+                Register::from_num(num).map_err(|_| invalid())
+            });
         }
+        Err(invalid())
+    }
+}
+
+const ABI_NAMES: [&'static str; 32] = [
+    "zero",
+    "ra",
+    "sp",
+    "gp",
+    "tp",
+    "t0",
+    "t1",
+    "t2",
+    "s0",
+    "s1",
+    "a0",
+    "a1",
+    "a2",
+    "a3",
+    "a4",
+    "a5",
+    "a6",
+    "a7",
+    "s2",
+    "s3",
+    "s4",
+    "s5",
+    "s6",
+    "s7",
+    "s8",
+    "s9",
+    "s10",
+    "s11",
+    "t3",
+    "t4",
+    "t5",
+    "t6",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RegisterError {
+    InvalidNumber(u32),
+    InvalidName(String),
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            RegisterError::InvalidNumber(num) => write!(f, "x{} is not a register", num),
+            RegisterError::InvalidName(ref name) => write!(f, "{} is not a register", name),
+        }
+    }
+}
+
+impl str::FromStr for Register {
+    type Err = RegisterError;
+
+    fn from_str(name: &str) -> Result<Register, RegisterError> {
+        Register::from_name(name)
+    }
+}
+
+/// Registers display with their ABI names, like `a0`.
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.abi_name())
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum UOpcode {
     LUI,
     AUIPC,
 }
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum UJOpcode {
     JAL,
 }
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum SBOpcode {
     BEQ,
     BNE,
@@ -93,11 +190,13 @@ pub enum SBOpcode {
     BLTU,
     BGEU,
 }
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum SOpcode {
     SB,
     SH,
     SW,
 }
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum IOpcode {
     JALR,
     LB,
@@ -113,6 +212,7 @@ pub enum IOpcode {
     ANDI,
     SCALL,
 }
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ROpcode {
     ADD,
     SUB,
@@ -125,12 +225,17 @@ pub enum ROpcode {
     OR,
     AND,
 }
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum RShiftOpcode {
     SLLI,
     SRLI,
     SRAI,
 }
 
+/// Immediates are stored the way the instruction uses them: I, S, SB
+/// and UJ immediates are sign-extended to 32 bits, and U immediates are
+/// the upper 20 bits, not yet shifted into place.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Instruction {
     RShift {
         opcode: RShiftOpcode,
@@ -173,3 +278,46 @@ pub enum Instruction {
         imm: u32,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Register, RegisterError};
+
+    #[test]
+    fn abi_names() {
+        let names = ["zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+                     "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+                     "t5", "t6"];
+        for (num, name) in names.iter().enumerate() {
+            let register = Register::from_num(num as u32).unwrap();
+            assert_eq!(Register::from_name(name), Ok(register));
+            assert_eq!(Register::from_name(&format!("x{}", num)), Ok(register));
+            assert_eq!(register.abi_name(), *name);
+        }
+        assert_eq!(Register::from_name("fp"), Register::from_name("s0"));
+        assert_eq!(Register::from_name("fp"), Ok(Register::X8));
+    }
+
+    #[test]
+    fn invalid_names() {
+        for name in ["x01", "x32", "x", "x-1", "x+1", "a8", "s12", "ZERO", ""].iter() {
+            assert_eq!(Register::from_name(name), Err(RegisterError::InvalidName(name.to_string())));
+        }
+    }
+
+    #[test]
+    fn invalid_numbers() {
+        assert_eq!(Register::from_num(31), Ok(Register::X31));
+        assert_eq!(Register::from_num(32), Err(RegisterError::InvalidNumber(32)));
+        assert_eq!(Register::from_num(!0), Err(RegisterError::InvalidNumber(!0)));
+    }
+
+    #[test]
+    fn display_parses_back() {
+        for num in 0..32 {
+            let register = Register::from_num(num).unwrap();
+            assert_eq!(register.to_string().parse::<Register>(), Ok(register));
+        }
+        assert_eq!(Register::X2.to_string(), "sp");
+    }
+}
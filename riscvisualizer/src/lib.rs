@@ -0,0 +1,8 @@
+#![feature(braced_empty_structs)]
+
+pub mod assembler;
+pub mod binary;
+pub mod interpreter;
+pub mod isa;
+pub mod memory;
+pub mod types;
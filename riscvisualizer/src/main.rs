@@ -1,11 +1,218 @@
-#![feature(braced_empty_structs)]
+extern crate riscvisualizer;
 
-mod assembler;
-mod interpreter;
-mod isa;
-mod memory;
-mod types;
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::process;
 
+use riscvisualizer::assembler;
+use riscvisualizer::interpreter::{self, Interpreter};
+use riscvisualizer::memory::{CacheConfig, CacheStats, HierarchyConfig, Prefetcher, ReplacementPolicy,
+                             WriteMissPolicy, WritePolicy};
+
+const USAGE: &'static str = "usage: riscvisualizer [--policies | --hierarchy [--l3] [--trace]] [--lines N] [--line-words N]
+                      [--ways N] [--seed N] [--write-through] [--no-write-allocate]
+                      [--prefetch next-line|stride] FILE";
+const MEMORY_WORDS: usize = 1 << 16;
+const MAX_STEPS: usize = 1000000;
+
+fn usage() -> ! {
+    writeln!(io::stderr(), "{}", USAGE).unwrap();
+    process::exit(2);
+}
+
+enum Mode {
+    // print the assembled program
+    Image,
+    // compare replacement policies for one cache
+    Policies,
+    // run through split L1 caches, an L2 and maybe an L3
+    Hierarchy,
+}
+
+const STATS_HEADER: &'static str =
+    "      hits     misses  hit rate writebacks  writes down prefetches     useful";
+
+/// Runs the program, printing every cache event along with the PC of
+/// the instruction that caused it, and every prefetch or invalidate
+/// call with the cycles its step took.
+fn run_traced(interpreter: &mut Interpreter) {
+    for _ in 0..MAX_STEPS {
+        if let Err(trap) = interpreter.step() {
+            println!("{:08x} {}", interpreter.pc(), trap);
+            break;
+        }
+        {
+            let action = interpreter.history().last().unwrap();
+            if let Some((call, address)) = action.cache_call {
+                let name = if call == interpreter::CALL_PREFETCH { "prefetch" } else { "invalidate" };
+                println!("{:08x} call {} {:08x} ({} cycles)", action.pc.0, name, address, action.cycles);
+            }
+            for &(cache, ref event) in action.cache_events.iter() {
+                println!("{:08x} {:<4} {}", action.pc.0, interpreter.caches()[cache].borrow().name(), event);
+            }
+        }
+        // Only the last step is needed
+        interpreter.forget_history();
+    }
+}
+
+fn stats_columns(stats: &CacheStats) -> String {
+    format!("{:>10} {:>10} {:>8.2}% {:>10} {:>12} {:>10} {:>10}",
+            stats.hits, stats.misses, 100.0 * stats.hit_rate(), stats.writebacks, stats.next_level_writes,
+            stats.prefetches, stats.useful_prefetches)
+}
+
+/// Assembles the file named on the command line and prints the memory
+/// image, one word per line. With `--policies`, runs the program with
+/// each cache replacement policy instead and prints their hit rates;
+/// with `--hierarchy`, runs it through several levels of cache and
+/// prints each level's statistics, after every cache event if `--trace`
+/// is given. The cache options set up the cache for `--policies` and
+/// the L1 caches for `--hierarchy`; lower levels are bigger. Programs
+/// can prefetch or invalidate lines themselves with an environment call
+/// (see `interpreter::CALL_PREFETCH`).
 fn main() {
-    println!("Hello, world!");
+    let mut path = None;
+    let mut mode = Mode::Image;
+    let mut l3 = false;
+    let mut trace = false;
+    let mut config = CacheConfig {
+        lines: 16,
+        line_words: 4,
+        ways: 4,
+        stall_cycles: 20,
+        policy: ReplacementPolicy::Lru,
+        write_policy: WritePolicy::WriteBack,
+        write_miss_policy: WriteMissPolicy::WriteAllocate,
+        prefetcher: Prefetcher::NoPrefetcher,
+    };
+    let mut seed = 1;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut number = || -> usize {
+            args.next().and_then(|value| value.parse().ok()).unwrap_or_else(|| usage())
+        };
+        match &arg[..] {
+            "--policies" => mode = Mode::Policies,
+            "--hierarchy" => mode = Mode::Hierarchy,
+            "--l3" => l3 = true,
+            "--trace" => trace = true,
+            "--lines" => config.lines = number(),
+            "--line-words" => config.line_words = number(),
+            "--ways" => config.ways = number(),
+            "--seed" => seed = number() as u64,
+            "--write-through" => config.write_policy = WritePolicy::WriteThrough,
+            "--no-write-allocate" => config.write_miss_policy = WriteMissPolicy::NoWriteAllocate,
+            "--prefetch" => {
+                config.prefetcher = match args.next() {
+                    Some(ref prefetcher) if prefetcher == "next-line" => Prefetcher::NextLine,
+                    Some(ref prefetcher) if prefetcher == "stride" => Prefetcher::Stride,
+                    _ => usage(),
+                };
+            }
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => usage(),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+    match mode {
+        Mode::Hierarchy => (),
+        _ if trace => usage(),
+        _ => (),
+    }
+
+    let mut source = String::new();
+    if let Err(err) = File::open(&path).and_then(|mut file| file.read_to_string(&mut source)) {
+        writeln!(io::stderr(), "{}: {}", path, err).unwrap();
+        process::exit(2);
+    }
+
+    let image = match assembler::assemble(&source) {
+        Ok(image) => image,
+        Err(errors) => {
+            for err in errors.iter() {
+                writeln!(io::stderr(), "{}\n", err.render(&path, &source)).unwrap();
+            }
+            writeln!(io::stderr(), "{} error(s)", errors.len()).unwrap();
+            process::exit(1);
+        }
+    };
+
+    if let Mode::Image = mode {
+        for (i, word) in image.words.iter().enumerate() {
+            println!("{:08x}: {:08x}", 4 * i, word);
+        }
+        return;
+    }
+
+    if config.lines == 0 || config.line_words == 0 || config.ways == 0 || config.lines % config.ways != 0 {
+        writeln!(io::stderr(), "{}: lines must be a nonzero multiple of ways", path).unwrap();
+        process::exit(2);
+    }
+    println!("{} lines of {} words, {}-way, {:?}, {:?}, {}", config.lines, config.line_words,
+             config.ways, config.write_policy, config.write_miss_policy, config.prefetcher);
+
+    let result = match mode {
+        Mode::Policies => {
+            let mut all = vec![ReplacementPolicy::Lru, ReplacementPolicy::Fifo, ReplacementPolicy::Random(seed)];
+            if config.ways.is_power_of_two() {
+                all.push(ReplacementPolicy::TreePseudoLru);
+            }
+
+            interpreter::compare_policies(MEMORY_WORDS, config, &image, &all, MAX_STEPS).map(|results| {
+                println!("{:<20} {} {:>10}", "policy", STATS_HEADER, "cycles");
+                for &(policy, ref stats, cycles) in results.iter() {
+                    println!("{:<20} {} {:>10}", policy.to_string(), stats_columns(stats), cycles);
+                }
+            })
+        }
+        Mode::Hierarchy => {
+            let hierarchy = HierarchyConfig {
+                // Only the L1 caches prefetch
+                l1i: config,
+                l1d: config,
+                l2: CacheConfig {
+                    lines: 8 * config.lines,
+                    stall_cycles: 2 * config.stall_cycles,
+                    prefetcher: Prefetcher::NoPrefetcher,
+                    ..config
+                },
+                l3: if l3 {
+                    Some(CacheConfig {
+                        lines: 32 * config.lines,
+                        stall_cycles: 4 * config.stall_cycles,
+                        prefetcher: Prefetcher::NoPrefetcher,
+                        ..config
+                    })
+                }
+                else {
+                    None
+                },
+            };
+
+            Interpreter::with_hierarchy(MEMORY_WORDS, hierarchy, &image).map(|mut interpreter| {
+                if trace {
+                    run_traced(&mut interpreter);
+                }
+                else {
+                    interpreter.set_keep_history(false);
+                    interpreter.run(MAX_STEPS);
+                }
+                println!("{:<20} {}", "level", STATS_HEADER);
+                for cache in interpreter.caches().iter() {
+                    let cache = cache.borrow();
+                    println!("{:<20} {}", cache.name(), stats_columns(&cache.stats()));
+                }
+                println!("{} cycles", interpreter.cycles());
+            })
+        }
+        Mode::Image => unreachable!(),
+    };
+
+    if result.is_err() {
+        writeln!(io::stderr(), "{}: program doesn't fit in memory", path).unwrap();
+        process::exit(1);
+    }
 }
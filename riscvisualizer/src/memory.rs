@@ -1,6 +1,9 @@
+use std::any::Any;
 use std::cell::RefCell;
+use std::fmt;
 use std::rc::Rc;
 
+use assembler;
 use types;
 use types::IsaType;
 
@@ -9,18 +12,39 @@ pub enum MemoryError {
     InvalidAddress,
 }
 
-pub struct MemoryAccess<T>(T, usize);
+pub struct MemoryAccess<T>(pub T, pub usize);
 pub type Result<T> = ::std::result::Result<MemoryAccess<T>, MemoryError>;
 
-trait MemoryInterface {
-    // fn prefetch(&mut self, address: types::Address);
-    // fn invalidate(&mut self, address: types::Address);
+pub trait MemoryInterface {
+    /// Brings the line holding `address` into any caches ahead of time,
+    /// returning the cycles the fill took. Inaccessible addresses are
+    /// ignored.
+    fn prefetch(&mut self, address: types::Address) -> usize;
+    /// Drops the line holding `address` from any caches, writing it
+    /// back first if it's dirty, and returns the cycles that took.
+    fn invalidate(&mut self, address: types::Address) -> usize;
 
     fn is_address_accessible(&self, address: types::Address) -> bool;
 
     fn read_word(&mut self, address: types::Address) -> Result<types::Word>;
     fn write_word(&mut self, address: types::Address, value: types::Word) -> Result<()>;
 
+    /// Reads a word without taking any cycles or disturbing a cache,
+    /// for debuggers and for undoing steps.
+    fn peek_word(&self, address: types::Address) -> ::std::result::Result<types::Word, MemoryError>;
+    /// Writes a word without taking any cycles or disturbing a cache.
+    fn poke_word(&mut self, address: types::Address, value: types::Word)
+                 -> ::std::result::Result<(), MemoryError>;
+
+    /// Captures bookkeeping that isn't part of memory's contents but
+    /// affects later accesses, such as cache tags, so that it can be
+    /// put back when a step is undone. Plain memory has none.
+    fn snapshot(&self) -> Option<Box<Any>> {
+        None
+    }
+
+    fn restore(&mut self, _snapshot: Box<Any>) {}
+
     // TODO: check address more thoroughly
     // TODO: get rid of panics
 
@@ -97,12 +121,791 @@ trait MemoryInterface {
     }
 }
 
-struct Memory {
+/// Flat main memory, addressed from 0. Every access takes the same
+/// number of cycles.
+pub struct Memory {
     memory: Vec<u32>,
+    latency: usize,
+}
+
+impl Memory {
+    pub fn new(words: usize, latency: usize) -> Memory {
+        Memory {
+            memory: vec![0; words],
+            latency: latency,
+        }
+    }
+
+    pub fn size_words(&self) -> usize {
+        self.memory.len()
+    }
+
+    pub fn latency(&self) -> usize {
+        self.latency
+    }
+
+    /// Copies `words` into memory starting at `address`, which must be
+    /// word-aligned. Nothing is copied unless all of it fits.
+    pub fn load(&mut self, address: types::Address, words: &[u32]) -> ::std::result::Result<(), MemoryError> {
+        let start = (address.0 / 4) as usize;
+        if address.0 % 4 != 0 || start + words.len() > self.memory.len() {
+            return Err(MemoryError::InvalidAddress);
+        }
+        self.memory[start..start + words.len()].copy_from_slice(words);
+        Ok(())
+    }
+
+    /// Clears memory and loads an assembled program at address 0.
+    pub fn load_image(&mut self, image: &assembler::Image) -> ::std::result::Result<(), MemoryError> {
+        if image.words.len() > self.memory.len() {
+            return Err(MemoryError::InvalidAddress);
+        }
+        for word in self.memory.iter_mut() {
+            *word = 0;
+        }
+        self.load(types::Word(0), &image.words)
+    }
+
+    fn check(&self, address: types::Address) -> ::std::result::Result<usize, MemoryError> {
+        if self.is_address_accessible(address) {
+            Ok((address.0 / 4) as usize)
+        }
+        else {
+            Err(MemoryError::InvalidAddress)
+        }
+    }
+}
+
+impl MemoryInterface for Memory {
+    // Nothing is cached in main memory
+    fn prefetch(&mut self, _address: types::Address) -> usize {
+        0
+    }
+
+    fn invalidate(&mut self, _address: types::Address) -> usize {
+        0
+    }
+
+    fn is_address_accessible(&self, address: types::Address) -> bool {
+        ((address.0 / 4) as usize) < self.memory.len()
+    }
+
+    fn read_word(&mut self, address: types::Address) -> Result<types::Word> {
+        let index = try!(self.check(address));
+        Ok(MemoryAccess(types::Word(self.memory[index]), self.latency))
+    }
+
+    fn write_word(&mut self, address: types::Address, value: types::Word) -> Result<()> {
+        let index = try!(self.check(address));
+        self.memory[index] = value.0;
+        Ok(MemoryAccess((), self.latency))
+    }
+
+    fn peek_word(&self, address: types::Address) -> ::std::result::Result<types::Word, MemoryError> {
+        let index = try!(self.check(address));
+        Ok(types::Word(self.memory[index]))
+    }
+
+    fn poke_word(&mut self, address: types::Address, value: types::Word)
+                 -> ::std::result::Result<(), MemoryError> {
+        let index = try!(self.check(address));
+        self.memory[index] = value.0;
+        Ok(())
+    }
+}
+
+/// Lets a memory be shared, e.g. between a cache and whoever loads
+/// programs into it.
+impl<T: MemoryInterface> MemoryInterface for Rc<RefCell<T>> {
+    fn prefetch(&mut self, address: types::Address) -> usize {
+        self.borrow_mut().prefetch(address)
+    }
+
+    fn invalidate(&mut self, address: types::Address) -> usize {
+        self.borrow_mut().invalidate(address)
+    }
+
+    fn is_address_accessible(&self, address: types::Address) -> bool {
+        self.borrow().is_address_accessible(address)
+    }
+
+    fn read_word(&mut self, address: types::Address) -> Result<types::Word> {
+        self.borrow_mut().read_word(address)
+    }
+
+    fn write_word(&mut self, address: types::Address, value: types::Word) -> Result<()> {
+        self.borrow_mut().write_word(address, value)
+    }
+
+    fn peek_word(&self, address: types::Address) -> ::std::result::Result<types::Word, MemoryError> {
+        self.borrow().peek_word(address)
+    }
+
+    fn poke_word(&mut self, address: types::Address, value: types::Word)
+                 -> ::std::result::Result<(), MemoryError> {
+        self.borrow_mut().poke_word(address, value)
+    }
+
+    fn write_halfword(&mut self, address: types::Address, value: types::HalfWord) -> Result<()> {
+        self.borrow_mut().write_halfword(address, value)
+    }
+
+    fn write_byte(&mut self, address: types::Address, value: types::Byte) -> Result<()> {
+        self.borrow_mut().write_byte(address, value)
+    }
+
+    fn snapshot(&self) -> Option<Box<Any>> {
+        self.borrow().snapshot()
+    }
+
+    fn restore(&mut self, snapshot: Box<Any>) {
+        self.borrow_mut().restore(snapshot)
+    }
+}
+
+/// Cycles for an access that hits in the cache.
+pub const CACHE_HIT_CYCLES: usize = 1;
+
+/// How a cache picks which line in a full set to evict. Empty lines
+/// are always filled first.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReplacementPolicy {
+    // least recently used
+    Lru,
+    // oldest line brought in, no matter how it's been used since
+    Fifo,
+    // any line, using a generator started from the given seed
+    Random(u64),
+    // approximates LRU with a binary tree of bits per set, pointing
+    // away from recently used halves; needs a power of two ways
+    TreePseudoLru,
+}
+
+impl fmt::Display for ReplacementPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> ::std::result::Result<(), fmt::Error> {
+        match *self {
+            ReplacementPolicy::Lru => write!(f, "LRU"),
+            ReplacementPolicy::Fifo => write!(f, "FIFO"),
+            ReplacementPolicy::Random(seed) => write!(f, "random (seed {})", seed),
+            ReplacementPolicy::TreePseudoLru => write!(f, "tree PLRU"),
+        }
+    }
+}
+
+/// What happens to memory when a write hits in the cache.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WritePolicy {
+    // memory is written on every write
+    WriteThrough,
+    // lines are marked dirty and written when they're evicted
+    WriteBack,
+}
+
+/// What happens when a write misses.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WriteMissPolicy {
+    // bring the line in, then write as if it had hit
+    WriteAllocate,
+    // write straight to memory and leave the cache alone
+    NoWriteAllocate,
+}
+
+/// Something a cache did during an access, for tracing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheEvent {
+    Hit(types::Address),
+    Miss(types::Address),
+    // a line was replaced; the address of its first word, and whether
+    // it was dirty and had to be written back
+    Evict { address: types::Address, dirty: bool },
+    // a word went to the next level without waiting for an eviction
+    WriteToNextLevel(types::Address),
+    // a line was brought in before it was needed
+    Prefetch(types::Address),
+    // a line was dropped on request
+    Invalidate(types::Address),
+}
+
+impl fmt::Display for CacheEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> ::std::result::Result<(), fmt::Error> {
+        match *self {
+            CacheEvent::Hit(address) => write!(f, "hit {:08x}", address),
+            CacheEvent::Miss(address) => write!(f, "miss {:08x}", address),
+            CacheEvent::Evict { address, dirty: true } => write!(f, "evict dirty line {:08x}", address),
+            CacheEvent::Evict { address, dirty: false } => write!(f, "evict line {:08x}", address),
+            CacheEvent::WriteToNextLevel(address) => write!(f, "write {:08x} to next level", address),
+            CacheEvent::Prefetch(address) => write!(f, "prefetch line {:08x}", address),
+            CacheEvent::Invalidate(address) => write!(f, "invalidate line {:08x}", address),
+        }
+    }
+}
+
+/// Hardware that guesses which lines will be needed soon and
+/// prefetches them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Prefetcher {
+    NoPrefetcher,
+    // on a miss, prefetch the line after the one that missed
+    NextLine,
+    // when two accesses in a row are the same distance apart, prefetch
+    // the next address that far along. Works best on a data cache,
+    // where instruction fetches don't interrupt the pattern.
+    Stride,
+}
+
+impl fmt::Display for Prefetcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> ::std::result::Result<(), fmt::Error> {
+        match *self {
+            Prefetcher::NoPrefetcher => write!(f, "no prefetcher"),
+            Prefetcher::NextLine => write!(f, "next-line prefetcher"),
+            Prefetcher::Stride => write!(f, "stride prefetcher"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CacheConfig {
+    // total number of lines
+    pub lines: usize,
+    pub line_words: usize,
+    // lines per set; 1 is direct-mapped, `lines` is fully associative
+    pub ways: usize,
+    // extra cycles to move a line to or from the next level, on top
+    // of the time the next level takes to access it
+    pub stall_cycles: usize,
+    pub policy: ReplacementPolicy,
+    pub write_policy: WritePolicy,
+    pub write_miss_policy: WriteMissPolicy,
+    pub prefetcher: Prefetcher,
+}
+
+/// The caches in a multi-level hierarchy: split level 1 instruction
+/// and data caches, sharing a unified level 2, optionally in front of
+/// a level 3.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HierarchyConfig {
+    pub l1i: CacheConfig,
+    pub l1d: CacheConfig,
+    pub l2: CacheConfig,
+    pub l3: Option<CacheConfig>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CacheLine {
+    pub valid: bool,
+    pub dirty: bool,
+    // brought in by a prefetch and not used since
+    pub prefetched: bool,
+    pub tag: u32,
+    // when the line was last accessed and brought in, for replacement
+    last_used: usize,
+    filled: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    // dirty lines written back to the next level
+    pub writebacks: usize,
+    // words written through, or around the cache on a miss
+    pub next_level_writes: usize,
+    // lines brought in by prefetching, and how many of those were used
+    // before being evicted
+    pub prefetches: usize,
+    pub useful_prefetches: usize,
+}
+
+impl CacheStats {
+    pub fn accesses(&self) -> usize {
+        self.hits + self.misses
+    }
+
+    /// Fraction of accesses that hit, or 0 if there weren't any.
+    pub fn hit_rate(&self) -> f64 {
+        if self.accesses() == 0 {
+            0.0
+        }
+        else {
+            self.hits as f64 / self.accesses() as f64
+        }
+    }
 }
 
-struct Cache {
-    main_memory: Rc<RefCell<Memory>>,
+// Everything step_back needs to put back
+struct CacheState {
+    sets: Vec<Vec<CacheLine>>,
+    tree_bits: Vec<Vec<bool>>,
+    stats: CacheStats,
+    clock: usize,
+    random: u64,
+    last_access: types::Address,
+    stride: types::Word,
+    next: Option<Box<Any>>,
+}
+
+/// A set-associative cache in front of the next level of the memory
+/// hierarchy: another cache or main memory. Caches only decide how long
+/// each access takes; values always live in main memory, so reading
+/// through the caches or around them gives the same answer.
+pub struct Cache {
+    name: String,
+    next: Box<MemoryInterface>,
+    config: CacheConfig,
     num_sets: usize,
-    sets: Vec<Vec<u32>>,
+    sets: Vec<Vec<CacheLine>>,
+    // tree pseudo-LRU bits for each set, as a heap: node n's children
+    // are 2n and 2n + 1, and entry 0 is unused
+    tree_bits: Vec<Vec<bool>>,
+    stats: CacheStats,
+    // counts accesses, to order them for replacement
+    clock: usize,
+    // xorshift state for random replacement
+    random: u64,
+    // for the stride prefetcher
+    last_access: types::Address,
+    stride: types::Word,
+    // since the last take_events
+    events: Vec<CacheEvent>,
+}
+
+impl Cache {
+    pub fn new(name: &str, next: Box<MemoryInterface>, config: CacheConfig) -> Cache {
+        assert!(config.lines > 0 && config.line_words > 0 && config.ways > 0,
+                "Cache needs at least one line, word and way: {:?}", config);
+        assert!(config.lines % config.ways == 0,
+                "Cache lines must divide evenly into sets: {:?}", config);
+        assert!(config.policy != ReplacementPolicy::TreePseudoLru || config.ways.is_power_of_two(),
+                "Tree pseudo-LRU needs a power of two ways: {:?}", config);
+
+        let num_sets = config.lines / config.ways;
+        let line = CacheLine {
+            valid: false,
+            dirty: false,
+            prefetched: false,
+            tag: 0,
+            last_used: 0,
+            filled: 0,
+        };
+        let seed = match config.policy {
+            // xorshift gets stuck at 0
+            ReplacementPolicy::Random(seed) if seed != 0 => seed,
+            _ => 1,
+        };
+
+        Cache {
+            name: name.to_owned(),
+            next: next,
+            config: config,
+            num_sets: num_sets,
+            sets: vec![vec![line; config.ways]; num_sets],
+            tree_bits: vec![vec![false; config.ways]; num_sets],
+            stats: CacheStats::default(),
+            clock: 0,
+            random: seed,
+            last_access: types::Word(0),
+            stride: types::Word(0),
+            events: vec![],
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn config(&self) -> CacheConfig {
+        self.config
+    }
+
+    pub fn sets(&self) -> &[Vec<CacheLine>] {
+        &self.sets
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Returns what the cache has done since the last call.
+    pub fn take_events(&mut self) -> Vec<CacheEvent> {
+        ::std::mem::replace(&mut self.events, vec![])
+    }
+
+    /// The set an address maps to and its tag within that set.
+    fn locate(&self, address: types::Address) -> (usize, u32) {
+        let line = address.0 / 4 / self.config.line_words as u32;
+        ((line % self.num_sets as u32) as usize, line / self.num_sets as u32)
+    }
+
+    /// Picks the line to evict from a full set.
+    fn victim(&mut self, set_index: usize) -> usize {
+        let set = &self.sets[set_index];
+        match self.config.policy {
+            ReplacementPolicy::Lru => (0..set.len()).min_by_key(|&way| set[way].last_used).unwrap(),
+            ReplacementPolicy::Fifo => (0..set.len()).min_by_key(|&way| set[way].filled).unwrap(),
+            ReplacementPolicy::Random(_) => {
+                self.random ^= self.random << 13;
+                self.random ^= self.random >> 7;
+                self.random ^= self.random << 17;
+                (self.random % set.len() as u64) as usize
+            }
+            ReplacementPolicy::TreePseudoLru => {
+                let bits = &self.tree_bits[set_index];
+                let (mut node, mut first, mut span) = (1, 0, set.len());
+                while span > 1 {
+                    span /= 2;
+                    if bits[node] {
+                        node = 2 * node + 1;
+                        first += span;
+                    }
+                    else {
+                        node = 2 * node;
+                    }
+                }
+                first
+            }
+        }
+    }
+
+    /// Points the tree bits on the path to `way` away from it.
+    fn touch_tree(&mut self, set_index: usize, way: usize) {
+        let bits = &mut self.tree_bits[set_index];
+        let (mut node, mut first, mut span) = (1, 0, self.config.ways);
+        while span > 1 {
+            span /= 2;
+            if way < first + span {
+                bits[node] = true;
+                node = 2 * node;
+            }
+            else {
+                bits[node] = false;
+                node = 2 * node + 1;
+                first += span;
+            }
+        }
+    }
+
+    /// The address of the first word of the line with this tag.
+    fn line_address(&self, set_index: usize, tag: u32) -> types::Address {
+        let line = tag * self.num_sets as u32 + set_index as u32;
+        types::Word(line * self.config.line_words as u32 * 4)
+    }
+
+    /// Writes the word at `address` to the next level, returning the
+    /// cycles it takes. The value itself is already there.
+    fn write_to_next(&mut self, address: types::Address) -> usize {
+        let value = self.next.peek_word(address).expect("writing an address that was accessible");
+        let MemoryAccess((), cycles) = self.next.write_word(address, value)
+            .expect("writing an address that was accessible");
+        cycles
+    }
+
+    fn find(&self, set_index: usize, tag: u32) -> Option<usize> {
+        self.sets[set_index].iter().position(|line| line.valid && line.tag == tag)
+    }
+
+    /// Writes back a dirty line and marks it invalid, returning the
+    /// cycles that took.
+    fn evict(&mut self, set_index: usize, way: usize) -> usize {
+        let old = self.sets[set_index][way];
+        let mut cycles = 0;
+        if old.valid {
+            let old_address = self.line_address(set_index, old.tag);
+            self.events.push(CacheEvent::Evict { address: old_address, dirty: old.dirty });
+            if old.dirty {
+                self.stats.writebacks += 1;
+                cycles += self.config.stall_cycles + self.write_to_next(old_address);
+            }
+        }
+        self.sets[set_index][way].valid = false;
+        cycles
+    }
+
+    /// The way a new line should go in: an empty one if there is one,
+    /// or else the victim.
+    fn free_way(&mut self, set_index: usize) -> usize {
+        match self.sets[set_index].iter().position(|line| !line.valid) {
+            Some(way) => way,
+            None => self.victim(set_index),
+        }
+    }
+
+    /// Brings in the line with this tag at `way`, evicting whatever was
+    /// there, and returns the cycles that took.
+    fn fill(&mut self, set_index: usize, way: usize, tag: u32) -> usize {
+        let mut cycles = self.evict(set_index, way);
+        let MemoryAccess(_, fill_cycles) = self.next.read_word(self.line_address(set_index, tag))
+            .expect("filling a line that was accessible");
+        cycles += self.config.stall_cycles + fill_cycles;
+        self.sets[set_index][way] = CacheLine {
+            valid: true,
+            dirty: false,
+            prefetched: false,
+            tag: tag,
+            last_used: self.clock,
+            filled: self.clock,
+        };
+        if self.config.policy == ReplacementPolicy::TreePseudoLru {
+            self.touch_tree(set_index, way);
+        }
+        cycles
+    }
+
+    /// Brings in the line holding `address` if it isn't already here,
+    /// and returns the cycles that took. The prefetch is dropped rather
+    /// than evict `demand`, the (set, way) of the line the program is
+    /// using.
+    fn prefetch_line(&mut self, address: types::Address, demand: Option<(usize, usize)>) -> usize {
+        if !self.is_address_accessible(address) {
+            return 0;
+        }
+        let (set_index, tag) = self.locate(address);
+        if self.find(set_index, tag).is_some() {
+            return 0;
+        }
+        let way = self.free_way(set_index);
+        if demand == Some((set_index, way)) {
+            return 0;
+        }
+        self.clock += 1;
+        self.stats.prefetches += 1;
+        self.events.push(CacheEvent::Prefetch(self.line_address(set_index, tag)));
+        let cycles = self.fill(set_index, way, tag);
+        self.sets[set_index][way].prefetched = true;
+        cycles
+    }
+
+    /// Looks up the line holding `address`, bringing it in if needed,
+    /// and returns how many cycles that took.
+    fn access(&mut self, address: types::Address, write: bool) -> usize {
+        let (set_index, tag) = self.locate(address);
+        self.clock += 1;
+        let clock = self.clock;
+        let mut cycles = CACHE_HIT_CYCLES;
+        // issued once this access is done with its line
+        let mut prefetch = None;
+
+        let way = match self.find(set_index, tag) {
+            Some(way) => {
+                self.stats.hits += 1;
+                self.events.push(CacheEvent::Hit(address));
+                if self.sets[set_index][way].prefetched {
+                    self.stats.useful_prefetches += 1;
+                    self.sets[set_index][way].prefetched = false;
+                }
+                way
+            }
+            None => {
+                self.stats.misses += 1;
+                self.events.push(CacheEvent::Miss(address));
+                if write && self.config.write_miss_policy == WriteMissPolicy::NoWriteAllocate {
+                    self.stats.next_level_writes += 1;
+                    self.events.push(CacheEvent::WriteToNextLevel(address));
+                    return cycles + self.write_to_next(address);
+                }
+
+                let way = self.free_way(set_index);
+                cycles += self.fill(set_index, way, tag);
+                if self.config.prefetcher == Prefetcher::NextLine {
+                    prefetch = Some(self.line_address(set_index, tag) + self.config.line_words as u32 * 4);
+                }
+                way
+            }
+        };
+
+        // Repeated accesses to one address, like a load and store to
+        // the same word, don't break up a pattern
+        let stride = address.wrapping_sub(self.last_access);
+        if self.config.prefetcher == Prefetcher::Stride && stride != types::Word(0) {
+            if stride == self.stride {
+                prefetch = Some(address.wrapping_add(stride));
+            }
+            self.stride = stride;
+            self.last_access = address;
+        }
+
+        if self.config.policy == ReplacementPolicy::TreePseudoLru {
+            self.touch_tree(set_index, way);
+        }
+        self.sets[set_index][way].last_used = clock;
+        if write {
+            match self.config.write_policy {
+                WritePolicy::WriteBack => self.sets[set_index][way].dirty = true,
+                WritePolicy::WriteThrough => {
+                    self.stats.next_level_writes += 1;
+                    self.events.push(CacheEvent::WriteToNextLevel(address));
+                    cycles += self.write_to_next(address);
+                }
+            }
+        }
+
+        // The prefetcher works alongside the program, so its fills
+        // don't stall it
+        if let Some(target) = prefetch {
+            self.prefetch_line(target, Some((set_index, way)));
+        }
+        cycles
+    }
+
+    fn check(&self, address: types::Address) -> ::std::result::Result<(), MemoryError> {
+        if self.is_address_accessible(address) {
+            Ok(())
+        }
+        else {
+            Err(MemoryError::InvalidAddress)
+        }
+    }
+}
+
+impl MemoryInterface for Cache {
+    fn prefetch(&mut self, address: types::Address) -> usize {
+        self.prefetch_line(address, None)
+    }
+
+    fn invalidate(&mut self, address: types::Address) -> usize {
+        let mut cycles = 0;
+        if self.is_address_accessible(address) {
+            let (set_index, tag) = self.locate(address);
+            if let Some(way) = self.find(set_index, tag) {
+                self.events.push(CacheEvent::Invalidate(self.line_address(set_index, tag)));
+                cycles += self.evict(set_index, way);
+            }
+        }
+        cycles + self.next.invalidate(address)
+    }
+
+    fn is_address_accessible(&self, address: types::Address) -> bool {
+        self.next.is_address_accessible(address)
+    }
+
+    fn read_word(&mut self, address: types::Address) -> Result<types::Word> {
+        try!(self.check(address));
+        let cycles = self.access(address, false);
+        let value = try!(self.next.peek_word(address));
+        Ok(MemoryAccess(value, cycles))
+    }
+
+    fn write_word(&mut self, address: types::Address, value: types::Word) -> Result<()> {
+        try!(self.check(address));
+        // Store the value first, so whatever this write sends on to the
+        // next level is already up to date
+        try!(self.next.poke_word(address, value));
+        let cycles = self.access(address, true);
+        Ok(MemoryAccess((), cycles))
+    }
+
+    // A partial store is one write to the cache, not a read and then a
+    // write, so a miss under NoWriteAllocate doesn't bring the line in
+
+    fn write_halfword(&mut self, address: types::Address, value: types::HalfWord) -> Result<()> {
+        try!(self.check(address));
+        let word = try!(self.next.peek_word(address));
+        let shift = 8 * (address & 0b10).0;
+        self.write_word(address, (word & !(0xFFFF << shift)) | (value.as_word() << shift))
+    }
+
+    fn write_byte(&mut self, address: types::Address, value: types::Byte) -> Result<()> {
+        try!(self.check(address));
+        let word = try!(self.next.peek_word(address));
+        let shift = 8 * (address % 4).0;
+        self.write_word(address, (word & !(0xFF << shift)) | (value.as_word() << shift))
+    }
+
+    fn peek_word(&self, address: types::Address) -> ::std::result::Result<types::Word, MemoryError> {
+        self.next.peek_word(address)
+    }
+
+    fn poke_word(&mut self, address: types::Address, value: types::Word)
+                 -> ::std::result::Result<(), MemoryError> {
+        self.next.poke_word(address, value)
+    }
+
+    fn snapshot(&self) -> Option<Box<Any>> {
+        Some(Box::new(CacheState {
+            sets: self.sets.clone(),
+            tree_bits: self.tree_bits.clone(),
+            stats: self.stats,
+            clock: self.clock,
+            random: self.random,
+            last_access: self.last_access,
+            stride: self.stride,
+            next: self.next.snapshot(),
+        }))
+    }
+
+    fn restore(&mut self, snapshot: Box<Any>) {
+        let state = *snapshot.downcast::<CacheState>().expect("snapshot from another kind of memory");
+        self.sets = state.sets;
+        self.tree_bits = state.tree_bits;
+        self.stats = state.stats;
+        self.clock = state.clock;
+        self.random = state.random;
+        self.last_access = state.last_access;
+        self.stride = state.stride;
+        if let Some(next) = state.next {
+            self.next.restore(next);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use types::{Byte, HalfWord, Word};
+
+    use super::{Cache, CacheConfig, Memory, MemoryAccess, MemoryInterface, Prefetcher, ReplacementPolicy,
+                WriteMissPolicy, WritePolicy};
+
+    fn new_cache(write_miss_policy: WriteMissPolicy) -> Cache {
+        Cache::new("L1", Box::new(Memory::new(64, 10)), CacheConfig {
+            lines: 4,
+            line_words: 2,
+            ways: 2,
+            stall_cycles: 5,
+            policy: ReplacementPolicy::Lru,
+            write_policy: WritePolicy::WriteBack,
+            write_miss_policy: write_miss_policy,
+            prefetcher: Prefetcher::NoPrefetcher,
+        })
+    }
+
+    #[test]
+    fn partial_stores_are_one_access() {
+        let mut cache = new_cache(WriteMissPolicy::NoWriteAllocate);
+        cache.poke_word(Word(8), Word(0x11223344)).unwrap();
+        cache.write_byte(Word(9), Byte(0xAA)).unwrap();
+        cache.write_halfword(Word(18), HalfWord(0xBBCC)).unwrap();
+
+        assert_eq!(cache.peek_word(Word(8)), Ok(Word(0x1122AA44)));
+        assert_eq!(cache.peek_word(Word(16)), Ok(Word(0xBBCC0000)));
+        assert_eq!(cache.stats().misses, 2);
+        assert_eq!(cache.stats().hits, 0);
+        assert!(cache.sets().iter().all(|set| set.iter().all(|line| !line.valid)));
+
+        let mut cache = new_cache(WriteMissPolicy::WriteAllocate);
+        cache.write_byte(Word(9), Byte(0xAA)).unwrap();
+        let MemoryAccess(byte, _) = cache.read_byte(Word(9)).unwrap();
+        assert_eq!(byte, Byte(0xAA));
+        assert_eq!((cache.stats().misses, cache.stats().hits), (1, 1));
+    }
+
+    #[test]
+    fn prefetch_keeps_demand_line() {
+        // One line, so the next line can only go where the demand line is
+        let mut cache = Cache::new("L1", Box::new(Memory::new(64, 10)), CacheConfig {
+            lines: 1,
+            line_words: 2,
+            ways: 1,
+            stall_cycles: 5,
+            policy: ReplacementPolicy::Fifo,
+            write_policy: WritePolicy::WriteBack,
+            write_miss_policy: WriteMissPolicy::WriteAllocate,
+            prefetcher: Prefetcher::NextLine,
+        });
+        cache.write_word(Word(0), Word(1)).unwrap();
+        cache.read_word(Word(4)).unwrap();
+
+        assert_eq!(cache.stats().prefetches, 0);
+        assert_eq!((cache.stats().hits, cache.stats().misses), (1, 1));
+        let line = cache.sets()[0][0];
+        assert!(line.valid && line.dirty && line.tag == 0);
+    }
 }
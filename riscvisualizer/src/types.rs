@@ -73,13 +73,13 @@ macro_rules! isa_type {
 
         impl fmt::LowerHex for $name {
             fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-                write!(f, "{:x}", self.0)
+                fmt::LowerHex::fmt(&self.0, f)
             }
         }
 
         impl fmt::UpperHex for $name {
             fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-                write!(f, "{:X}", self.0)
+                fmt::UpperHex::fmt(&self.0, f)
             }
         }
     }
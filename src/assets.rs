@@ -1,4 +1,6 @@
 use std;
+use std::collections::HashMap;
+
 use yaml_rust;
 
 #[derive(Debug)]
@@ -12,6 +14,23 @@ pub trait Decodable: Sized {
     fn decode(doc: &yaml_rust::Yaml) -> Result<Self>;
 }
 
+pub trait Encodable {
+    fn encode(&self) -> yaml_rust::Yaml;
+}
+
+/// Builds a YAML hash from string keys.
+pub fn hash(entries: Vec<(&str, yaml_rust::Yaml)>) -> yaml_rust::Yaml {
+    let mut result = yaml_rust::yaml::Hash::new();
+    for (key, value) in entries {
+        result.insert(yaml_rust::Yaml::String(key.to_owned()), value);
+    }
+    yaml_rust::Yaml::Hash(result)
+}
+
+pub fn strings<'a, I: IntoIterator<Item=&'a String>>(values: I) -> yaml_rust::Yaml {
+    yaml_rust::Yaml::Array(values.into_iter().map(|v| yaml_rust::Yaml::String(v.clone())).collect())
+}
+
 macro_rules! get_field {
     ( $doc: expr, $field: expr, $as_: ident ) => {
         as_value!( $doc[$field], $as_ )
@@ -23,3 +42,37 @@ macro_rules! as_value {
         try!($doc.$as_().ok_or(assets::DecodeError::TypeError(format!("Wrong value type"))))
     }
 }
+
+macro_rules! get_optional_field {
+    ( $doc: expr, $field: expr, $as_: ident ) => {
+        if $doc[$field].is_badvalue() {
+            None
+        }
+        else {
+            Some(as_value!( $doc[$field], $as_ ))
+        }
+    }
+}
+
+pub fn decode_strings(doc: &yaml_rust::Yaml) -> Result<Vec<String>> {
+    let wrong_type = || DecodeError::TypeError(format!("Expected a list of strings"));
+
+    let mut result = vec![];
+    for value in try!(doc.as_vec().ok_or_else(&wrong_type)).iter() {
+        result.push(try!(value.as_str().ok_or_else(&wrong_type)).to_owned());
+    }
+    Ok(result)
+}
+
+/// Decodes a list of documents keyed by their ID.
+pub fn load_table<T>(docs: Vec<yaml_rust::Yaml>) -> Result<HashMap<String, T>>
+    where (String, T): Decodable {
+    let mut result = HashMap::with_capacity(docs.len());
+
+    for entry in docs.iter().map(Decodable::decode) {
+        let (key, value): (String, T) = try!(entry);
+        result.insert(key, value);
+    }
+
+    Ok(result)
+}
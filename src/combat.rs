@@ -0,0 +1,333 @@
+use std::cmp::max;
+
+use yaml_rust;
+
+use assets;
+use dice;
+use player::{Attribute, Player, Stat};
+
+/// CPU spent on each hack attempt.
+pub const HACK_CPU_COST: i32 = 20;
+/// Game time that passes in each round of combat.
+pub const ROUND_SECONDS: i64 = 6;
+
+pub struct CombatStats {
+    pub hp: i32,
+    pub attack: i32,
+    pub defense: i32,
+    pub reflexes: i32,
+    // Rewards dropped on defeat
+    pub credits: u32,
+    pub loot: Vec<String>,
+}
+
+impl assets::Decodable for CombatStats {
+    fn decode(doc: &yaml_rust::Yaml) -> assets::Result<CombatStats> {
+        let mut loot = vec![];
+        if let Some(items) = get_optional_field!(doc, "loot", as_vec) {
+            for item_id in items.iter() {
+                loot.push(as_value!(item_id, as_str).to_owned());
+            }
+        }
+
+        Ok(CombatStats {
+            hp: get_field!(doc, "hp", as_i64) as i32,
+            attack: get_field!(doc, "attack", as_i64) as i32,
+            defense: get_optional_field!(doc, "defense", as_i64).unwrap_or(0) as i32,
+            reflexes: get_optional_field!(doc, "reflexes", as_i64).unwrap_or(0) as i32,
+            credits: get_optional_field!(doc, "credits", as_i64).unwrap_or(0) as u32,
+            loot: loot,
+        })
+    }
+}
+
+pub struct Enemy {
+    pub id: String,
+    pub name: String,
+    pub hp: Stat,
+    pub attack: i32,
+    pub defense: i32,
+    pub reflexes: i32,
+    pub defending: bool,
+}
+
+impl Enemy {
+    pub fn new(id: &str, name: &str, stats: &CombatStats) -> Enemy {
+        Enemy {
+            id: id.to_owned(),
+            name: name.to_owned(),
+            hp: Stat::new(stats.hp),
+            attack: stats.attack,
+            defense: stats.defense,
+            reflexes: stats.reflexes,
+            defending: false,
+        }
+    }
+
+    pub fn is_alive(&self) -> bool {
+        !self.hp.is_depleted()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Combatant {
+    Player,
+    Enemy(usize),
+}
+
+pub enum EnemyAction {
+    Attack(i32),
+    Defend,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Victory,
+    Defeat,
+}
+
+pub struct Combat {
+    pub enemies: Vec<Enemy>,
+    // turn order, fixed for the whole fight
+    pub order: Vec<Combatant>,
+    pub player_defending: bool,
+    pub round: u32,
+}
+
+fn halve_if(damage: i32, defending: bool) -> i32 {
+    if defending {
+        max(1, damage / 2)
+    }
+    else {
+        damage
+    }
+}
+
+impl Combat {
+    pub fn new(enemies: Vec<Enemy>) -> Combat {
+        Combat {
+            enemies: enemies,
+            order: vec![],
+            player_defending: false,
+            round: 0,
+        }
+    }
+
+    /// Rolls d20 + reflexes modifier for everyone and sets the turn
+    /// order, highest first. The player wins ties. Returns the rolls in
+    /// turn order.
+    pub fn roll_initiative(&mut self, player: &Player, rng: &mut dice::Rng) -> Vec<(Combatant, i32)> {
+        let reflexes = player.attribute(Attribute::Reflexes);
+        let mut rolls = vec![(Combatant::Player, rng.roll(20) as i32 + dice::modifier(reflexes))];
+        for (i, enemy) in self.enemies.iter().enumerate() {
+            rolls.push((Combatant::Enemy(i), rng.roll(20) as i32 + dice::modifier(enemy.reflexes)));
+        }
+        // Stable sort, so the player stays ahead of equal enemies
+        rolls.sort_by(|a, b| b.1.cmp(&a.1));
+
+        self.order = rolls.iter().map(|&(combatant, _)| combatant).collect();
+        rolls
+    }
+
+    /// The difficulty of sneaking past these enemies.
+    pub fn stealth_difficulty(&self) -> i32 {
+        10 + self.enemies.iter().map(|enemy| dice::modifier(enemy.reflexes)).max().unwrap_or(0)
+    }
+
+    /// Finds a living enemy by name. An empty name picks the only
+    /// living enemy, if there is exactly one.
+    pub fn find_target(&self, name: &str) -> Option<usize> {
+        let living: Vec<usize> = (0..self.enemies.len()).filter(|&i| self.enemies[i].is_alive()).collect();
+        if name.is_empty() {
+            return if living.len() == 1 { Some(living[0]) } else { None };
+        }
+
+        let name = name.to_lowercase();
+        living.into_iter().find(|&i| {
+            let enemy = &self.enemies[i];
+            enemy.id == name || enemy.name.to_lowercase() == name
+        })
+    }
+
+    pub fn player_attack(&mut self, player: &Player, target: usize, weapon_damage: i32) -> i32 {
+        let enemy = &mut self.enemies[target];
+        let damage = max(1, 2 * player.attribute(Attribute::Strength) + weapon_damage - enemy.defense);
+        let damage = halve_if(damage, enemy.defending);
+        enemy.hp.reduce(damage);
+        damage
+    }
+
+    /// Hacks ignore armor but cost CPU. Returns None if the player
+    /// doesn't have enough CPU left.
+    pub fn player_hack(&mut self, player: &mut Player, target: usize) -> Option<i32> {
+        if player.cpu.current < HACK_CPU_COST {
+            return None;
+        }
+        player.cpu.reduce(HACK_CPU_COST);
+
+        let enemy = &mut self.enemies[target];
+        let damage = halve_if(3 * player.attribute(Attribute::Intellect), enemy.defending);
+        enemy.hp.reduce(damage);
+        Some(damage)
+    }
+
+    pub fn enemy_turn(&mut self, index: usize, player: &mut Player) -> EnemyAction {
+        let player_defending = self.player_defending;
+        let enemy = &mut self.enemies[index];
+
+        // Badly hurt enemies turtle up, but never twice in a row
+        if !enemy.defending && enemy.hp.current * 4 <= enemy.hp.max {
+            enemy.defending = true;
+            return EnemyAction::Defend;
+        }
+        enemy.defending = false;
+
+        let damage = max(1, enemy.attack - player.attribute(Attribute::Reflexes));
+        let damage = halve_if(damage, player_defending);
+        player.hp.reduce(damage);
+        EnemyAction::Attack(damage)
+    }
+
+    pub fn outcome(&self, player: &Player) -> Option<Outcome> {
+        if player.hp.is_depleted() {
+            Some(Outcome::Defeat)
+        }
+        else if self.enemies.iter().all(|enemy| !enemy.is_alive()) {
+            Some(Outcome::Victory)
+        }
+        else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dice::Rng;
+    use player::{Attribute, Player};
+
+    use super::{Combat, Combatant, CombatStats, Enemy, EnemyAction, Outcome, HACK_CPU_COST};
+
+    fn enemy(hp: i32, attack: i32, defense: i32, reflexes: i32) -> Enemy {
+        let stats = CombatStats {
+            hp: hp,
+            attack: attack,
+            defense: defense,
+            reflexes: reflexes,
+            credits: 0,
+            loot: vec![],
+        };
+        Enemy::new("thug", "Thug", &stats)
+    }
+
+    #[test]
+    fn initiative() {
+        let player = Player::new("Ana");
+        let mut tied = false;
+        for seed in 1..100 {
+            let mut combat = Combat::new(vec![enemy(10, 5, 0, 5), enemy(10, 5, 0, 5)]);
+            let rolls = combat.roll_initiative(&player, &mut Rng::new(seed));
+
+            assert_eq!(rolls.len(), 3);
+            assert_eq!(combat.order, rolls.iter().map(|&(combatant, _)| combatant).collect::<Vec<_>>());
+            for combatant in &[Combatant::Player, Combatant::Enemy(0), Combatant::Enemy(1)] {
+                assert!(combat.order.contains(combatant));
+            }
+            for pair in rolls.windows(2) {
+                assert!(pair[0].1 >= pair[1].1, "seed {}: {:?}", seed, rolls);
+                if pair[0].1 == pair[1].1 && pair[1].0 == Combatant::Player {
+                    panic!("seed {}: the player lost a tie: {:?}", seed, rolls);
+                }
+            }
+            tied |= rolls.windows(2).any(|pair| pair[0].1 == pair[1].1 && pair[0].0 == Combatant::Player);
+        }
+        assert!(tied, "no seed tied the player with an enemy");
+
+        // The same seed gives the same order
+        let mut first = Combat::new(vec![enemy(10, 5, 0, 5)]);
+        let mut second = Combat::new(vec![enemy(10, 5, 0, 5)]);
+        assert_eq!(first.roll_initiative(&player, &mut Rng::new(42)),
+                   second.roll_initiative(&player, &mut Rng::new(42)));
+    }
+
+    #[test]
+    fn player_damage() {
+        let mut player = Player::new("Ana");
+        player.set_attribute(Attribute::Strength, 7);
+        player.set_attribute(Attribute::Intellect, 6);
+        let mut combat = Combat::new(vec![enemy(50, 5, 4, 5), enemy(50, 5, 100, 5)]);
+
+        // 2 * strength + weapon - defense
+        assert_eq!(combat.player_attack(&player, 0, 3), 13);
+        assert_eq!(combat.enemies[0].hp.current, 37);
+        // Always at least 1
+        assert_eq!(combat.player_attack(&player, 1, 3), 1);
+
+        // Hacks do 3 * intellect, ignoring defense
+        assert_eq!(combat.player_hack(&mut player, 1), Some(18));
+        assert_eq!(combat.enemies[1].hp.current, 31);
+        assert_eq!(player.cpu.current, 100 - HACK_CPU_COST);
+        player.cpu.current = HACK_CPU_COST - 1;
+        assert_eq!(combat.player_hack(&mut player, 1), None);
+        assert_eq!(combat.enemies[1].hp.current, 31);
+        assert_eq!(player.cpu.current, HACK_CPU_COST - 1);
+    }
+
+    #[test]
+    fn defending() {
+        let mut player = Player::new("Ana");
+        let mut combat = Combat::new(vec![enemy(40, 5, 4, 5), enemy(40, 5, 100, 5)]);
+
+        combat.enemies[0].defending = true;
+        combat.enemies[1].defending = true;
+        assert_eq!(combat.player_attack(&player, 0, 3), 4);
+        // Halving never goes below 1
+        assert_eq!(combat.player_attack(&player, 1, 3), 1);
+        assert_eq!(combat.player_hack(&mut player, 0), Some(7));
+
+        // attack - reflexes, halved while the player defends
+        let mut combat = Combat::new(vec![enemy(40, 14, 0, 5)]);
+        match combat.enemy_turn(0, &mut player) {
+            EnemyAction::Attack(damage) => assert_eq!(damage, 9),
+            EnemyAction::Defend => panic!("a healthy enemy defended"),
+        }
+        combat.player_defending = true;
+        match combat.enemy_turn(0, &mut player) {
+            EnemyAction::Attack(damage) => assert_eq!(damage, 4),
+            EnemyAction::Defend => panic!("a healthy enemy defended"),
+        }
+        assert_eq!(player.hp.current, 87);
+
+        // Badly hurt enemies defend, but not twice in a row
+        combat.enemies[0].hp.current = 10;
+        match combat.enemy_turn(0, &mut player) {
+            EnemyAction::Defend => assert!(combat.enemies[0].defending),
+            EnemyAction::Attack(_) => panic!("a badly hurt enemy attacked"),
+        }
+        match combat.enemy_turn(0, &mut player) {
+            EnemyAction::Attack(_) => assert!(!combat.enemies[0].defending),
+            EnemyAction::Defend => panic!("the enemy defended twice in a row"),
+        }
+    }
+
+    #[test]
+    fn outcomes() {
+        let mut player = Player::new("Ana");
+        let mut combat = Combat::new(vec![enemy(10, 5, 0, 5), enemy(10, 5, 0, 5)]);
+        assert_eq!(combat.outcome(&player), None);
+
+        combat.enemies[0].hp.reduce(10);
+        assert_eq!(combat.outcome(&player), None);
+        assert_eq!(combat.find_target(""), Some(1));
+
+        combat.enemies[1].hp.reduce(10);
+        assert_eq!(combat.outcome(&player), Some(Outcome::Victory));
+
+        let mut combat = Combat::new(vec![enemy(10, 5, 0, 5)]);
+        player.hp.reduce(100);
+        assert_eq!(combat.outcome(&player), Some(Outcome::Defeat));
+        // Going down together is still a defeat
+        combat.enemies[0].hp.reduce(10);
+        assert_eq!(combat.outcome(&player), Some(Outcome::Defeat));
+    }
+}
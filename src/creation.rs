@@ -0,0 +1,107 @@
+use player::{Attribute, Player};
+use ui::Frontend;
+use ui::input::InputError;
+
+const OFFICER: &'static str = "Customs Officer";
+const STARTING_POINTS: i32 = 6;
+
+struct Background {
+    name: &'static str,
+    description: &'static str,
+    bonuses: &'static [(Attribute, i32)],
+    credits: u32,
+    cpu: i32,
+}
+
+static BACKGROUNDS: [Background; 3] = [
+    Background {
+        name: "Contract muscle",
+        description: "Here for work. The kind that pays in cash.",
+        bonuses: &[(Attribute::Strength, 2), (Attribute::Reflexes, 1)],
+        credits: 50,
+        cpu: 80,
+    },
+    Background {
+        name: "Netrunner",
+        description: "Visiting family. Definitely not the local datacenter.",
+        bonuses: &[(Attribute::Intellect, 2), (Attribute::Reflexes, 1)],
+        credits: 20,
+        cpu: 140,
+    },
+    Background {
+        name: "Fixer",
+        description: "Business. Everyone's business, really.",
+        bonuses: &[(Attribute::Charisma, 2), (Attribute::Intellect, 1)],
+        credits: 150,
+        cpu: 100,
+    },
+];
+
+fn ask_name(ui: &Frontend) -> Result<String, InputError> {
+    ui.say(OFFICER, "What is your name?");
+    loop {
+        let name = try!(ui.prompt("name>"));
+        let name = name.trim();
+        if !name.is_empty() {
+            return Ok(name.to_owned());
+        }
+        ui.say(OFFICER, "I need a name for the form.");
+    }
+}
+
+fn ask_background(ui: &Frontend) -> Result<&'static Background, InputError> {
+    ui.say(OFFICER, "And what brings you to the city?");
+    for (i, background) in BACKGROUNDS.iter().enumerate() {
+        ui.display(&format!("[{}] {}: \"{}\"", i + 1, background.name, background.description));
+    }
+
+    let choice = try!(ui.prompt_number("background>", 1, BACKGROUNDS.len() as i32));
+    Ok(&BACKGROUNDS[choice as usize - 1])
+}
+
+fn allocate_points(ui: &Frontend, player: &mut Player) -> Result<(), InputError> {
+    ui.display(&format!("You have {} points to spend on your attributes.", STARTING_POINTS));
+
+    // Go around the attributes again until every point is spent
+    let mut remaining = STARTING_POINTS;
+    while remaining > 0 {
+        for attr in Attribute::all() {
+            if remaining == 0 {
+                break;
+            }
+
+            let current = player.attribute(*attr);
+            let prompt = format!("{} is {}. Points to add ({} left)>", attr.name(), current, remaining);
+            let points = try!(ui.prompt_number(&prompt, 0, remaining));
+
+            player.set_attribute(*attr, current + points);
+            remaining -= points;
+        }
+
+        if remaining > 0 {
+            ui.display(&format!("You still have {} points to spend.", remaining));
+        }
+    }
+    Ok(())
+}
+
+pub fn create_character(ui: &Frontend) -> Result<Player, InputError> {
+    ui.display("You're next up in line.");
+
+    let mut player = Player::new(try!(ask_name(ui)));
+
+    let background = try!(ask_background(ui));
+    for &(attr, bonus) in background.bonuses {
+        let value = player.attribute(attr);
+        player.set_attribute(attr, value + bonus);
+    }
+    player.credits = background.credits;
+    player.cpu.max = background.cpu;
+    player.cpu.current = background.cpu;
+
+    try!(allocate_points(ui, &mut player));
+
+    ui.say(OFFICER, "Very well, proceed on.");
+
+    Ok(player)
+}
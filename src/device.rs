@@ -0,0 +1,348 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use chrono;
+use chrono::Timelike;
+use riscvisualizer::interpreter::Interpreter;
+use riscvisualizer::memory::{Memory, MemoryInterface};
+use riscvisualizer::types::Word;
+use yaml_rust;
+
+use assets;
+use player::Player;
+
+/// Cycles charged for each access to device memory. Devices have no
+/// caches.
+const ACCESS_CYCLES: usize = 1;
+const DEFAULT_MEMORY_WORDS: usize = 128;
+/// Instructions a device executes each turn.
+const DEFAULT_CYCLES: usize = 64;
+
+pub enum Sensor {
+    // 1 if the world flag is set
+    Flag(String),
+    // 1 if the player is carrying the item (ID)
+    Item(String),
+    // minutes since midnight
+    Time,
+}
+
+pub enum Actuator {
+    // sets the world flag when nonzero, clears it when zero
+    Flag(String),
+}
+
+impl assets::Decodable for Sensor {
+    fn decode(doc: &yaml_rust::Yaml) -> assets::Result<Sensor> {
+        if let Some(flag) = get_optional_field!(doc, "flag", as_str) {
+            Ok(Sensor::Flag(flag.to_owned()))
+        }
+        else if let Some(item) = get_optional_field!(doc, "item", as_str) {
+            Ok(Sensor::Item(item.to_owned()))
+        }
+        else if doc.as_str() == Some("time") {
+            Ok(Sensor::Time)
+        }
+        else {
+            Err(assets::DecodeError::TypeError(format!("Sensor needs a flag, item or time")))
+        }
+    }
+}
+
+impl assets::Decodable for Actuator {
+    fn decode(doc: &yaml_rust::Yaml) -> assets::Result<Actuator> {
+        Ok(Actuator::Flag(get_field!(doc, "flag", as_str).to_owned()))
+    }
+}
+
+pub struct Device {
+    pub name: String,
+    pub description: String,
+    pub program: Vec<u32>,
+    // address : sensor or actuator
+    pub sensors: Vec<(u32, Sensor)>,
+    pub actuators: Vec<(u32, Actuator)>,
+    pub cycles: usize,
+    // Sensors and actuators are just addresses in this memory that the
+    // game reads and writes between turns
+    memory: Rc<RefCell<Memory>>,
+    interpreter: Interpreter,
+}
+
+impl Device {
+    fn new(name: String, description: String, memory_words: usize) -> Device {
+        let memory = Rc::new(RefCell::new(Memory::new(memory_words, ACCESS_CYCLES)));
+        Device {
+            name: name,
+            description: description,
+            program: vec![],
+            sensors: vec![],
+            actuators: vec![],
+            cycles: DEFAULT_CYCLES,
+            interpreter: Device::interpreter(memory.clone(), memory_words),
+            memory: memory,
+        }
+    }
+
+    fn interpreter(memory: Rc<RefCell<Memory>>, memory_words: usize) -> Interpreter {
+        let mut interpreter = Interpreter::with_memory(Box::new(memory), memory_words);
+        // Devices run for the whole game and are never stepped back
+        interpreter.set_keep_history(false);
+        interpreter
+    }
+
+    /// Checks that the program fits in the device's memory.
+    pub fn check_program(&self, program: &[u32]) -> Result<(), String> {
+        let memory_words = self.memory.borrow().size_words();
+        if program.len() > memory_words {
+            return Err(format!("The program is {} words, but the {} only has {} words of memory.",
+                               program.len(), self.name, memory_words));
+        }
+        Ok(())
+    }
+
+    /// Replaces the device's program and restarts it. Fails if the
+    /// program doesn't fit in the device's memory.
+    pub fn reprogram(&mut self, program: Vec<u32>) -> Result<(), String> {
+        try!(self.check_program(&program));
+        // Start from cleared memory
+        let memory_words = self.memory.borrow().size_words();
+        let mut memory = Memory::new(memory_words, ACCESS_CYCLES);
+        memory.load(Word(0), &program).expect("the program was checked to fit");
+        self.memory = Rc::new(RefCell::new(memory));
+        self.interpreter = Device::interpreter(self.memory.clone(), memory_words);
+        self.program = program;
+        Ok(())
+    }
+
+    /// Updates the sensors, runs the program for a turn, then applies
+    /// the actuators to the world.
+    pub fn run(&mut self, player: &Player, flags: &mut HashSet<String>,
+               time: &chrono::DateTime<chrono::UTC>) {
+        {
+            let mut memory = self.memory.borrow_mut();
+            for &(address, ref sensor) in self.sensors.iter() {
+                let value = match *sensor {
+                    Sensor::Flag(ref flag) => flags.contains(flag) as u32,
+                    Sensor::Item(ref id) => player.inventory.contains(id) as u32,
+                    Sensor::Time => time.hour() * 60 + time.minute(),
+                };
+                memory.poke_word(Word(address), Word(value)).expect("sensors are checked to be in memory");
+            }
+        }
+
+        // A trap halts the device until it's reprogrammed. Environment
+        // calls trap too, except the interpreter's cache calls (a0 = 1 or
+        // 2, see `interpreter::CALL_PREFETCH`), which do nothing and take
+        // no extra cycles here since devices have no caches.
+        for _ in 0..self.cycles {
+            if self.interpreter.step().is_err() {
+                break;
+            }
+        }
+
+        let memory = self.memory.borrow();
+        for &(address, ref actuator) in self.actuators.iter() {
+            match *actuator {
+                Actuator::Flag(ref flag) => {
+                    let value = memory.peek_word(Word(address)).expect("actuators are checked to be in memory");
+                    if value.0 != 0 {
+                        flags.insert(flag.clone());
+                    }
+                    else {
+                        flags.remove(flag);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The program as address/word pairs, followed by the memory map.
+    pub fn listing(&self) -> Vec<String> {
+        let mut lines: Vec<String> = self.program.iter().enumerate()
+            .map(|(i, word)| format!("{:04x}: {:08x}", 4 * i, word))
+            .collect();
+
+        for &(address, ref sensor) in self.sensors.iter() {
+            let name = match *sensor {
+                Sensor::Flag(ref flag) => format!("flag {}", flag),
+                Sensor::Item(ref id) => format!("carrying {}", id),
+                Sensor::Time => "minutes since midnight".to_owned(),
+            };
+            lines.push(format!("{:04x}: sensor ({})", address, name));
+        }
+        for &(address, Actuator::Flag(ref flag)) in self.actuators.iter() {
+            lines.push(format!("{:04x}: actuator (flag {})", address, flag));
+        }
+
+        lines
+    }
+}
+
+fn decode_map<T: assets::Decodable>(doc: &yaml_rust::Yaml, field: &str) -> assets::Result<Vec<(u32, T)>> {
+    let mut result = vec![];
+    if let Some(entries) = get_optional_field!(doc, field, as_hash) {
+        for (address, entry) in entries.iter() {
+            result.push((as_value!(address, as_i64) as u32, try!(T::decode(entry))));
+        }
+    }
+    Ok(result)
+}
+
+impl assets::Decodable for (String, Device) {
+    fn decode(doc: &yaml_rust::Yaml) -> assets::Result<(String, Device)> {
+        let id = get_field!(doc, "id", as_str);
+        let name = get_field!(doc, "name", as_str);
+        let desc = get_field!(doc, "description", as_str);
+        let memory_words = get_optional_field!(doc, "memory_words", as_i64).unwrap_or(DEFAULT_MEMORY_WORDS as i64);
+        if memory_words <= 0 {
+            return Err(assets::DecodeError::TypeError(
+                format!("Device {} needs at least one word of memory, not {}", id, memory_words)));
+        }
+
+        let mut program = vec![];
+        for word in get_field!(doc, "program", as_vec).iter() {
+            program.push(as_value!(word, as_i64) as u32);
+        }
+
+        let mut device = Device::new(name.to_owned(), desc.to_owned(), memory_words as usize);
+        device.sensors = try!(decode_map(doc, "sensors"));
+        device.actuators = try!(decode_map(doc, "actuators"));
+        let addresses = device.sensors.iter().map(|&(address, _)| address)
+            .chain(device.actuators.iter().map(|&(address, _)| address));
+        for address in addresses {
+            if !device.memory.borrow().is_address_accessible(Word(address)) {
+                return Err(assets::DecodeError::TypeError(
+                    format!("Device {} maps {:#x} outside its {} words of memory", id, address, memory_words)));
+            }
+        }
+        if let Some(cycles) = get_optional_field!(doc, "cycles", as_i64) {
+            device.cycles = cycles as usize;
+        }
+        try!(device.reprogram(program).map_err(assets::DecodeError::TypeError));
+
+        Ok((id.to_owned(), device))
+    }
+}
+
+pub fn load_devices(docs: Vec<yaml_rust::Yaml>) -> assets::Result<HashMap<String, Device>> {
+    assets::load_table(docs)
+}
+
+/// Finds the position of the device in `ids` whose name or ID matches
+/// `name`, ignoring case.
+pub fn find_by_name(ids: &[String], devices: &HashMap<String, Device>, name: &str) -> Option<usize> {
+    let name = name.to_lowercase();
+    ids.iter().position(|id| {
+        *id == name || devices.get(id).map_or(false, |device| device.name.to_lowercase() == name)
+    })
+}
+
+/// Parses a program written as hexadecimal machine words, with or
+/// without a `0x` prefix.
+pub fn parse_program(words: &[String]) -> Result<Vec<u32>, String> {
+    let mut program = vec![];
+    for word in words {
+        let digits = word.trim_left_matches("0x");
+        match u32::from_str_radix(digits, 16) {
+            Ok(value) => program.push(value),
+            Err(_) => return Err(format!("{} is not a machine word", word)),
+        }
+    }
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use chrono;
+    use chrono::offset::TimeZone;
+    use riscvisualizer::assembler;
+    use yaml_rust::YamlLoader;
+
+    use assets::{self, Decodable};
+    use player::Player;
+
+    use super::Device;
+
+    fn decode(source: &str) -> assets::Result<Device> {
+        let docs = YamlLoader::load_from_str(source).unwrap();
+        <(String, Device)>::decode(&docs[0]).map(|(_, device)| device)
+    }
+
+    fn program(source: &str) -> String {
+        let image = assembler::assemble(source).ok().expect("program failed to assemble");
+        let words: Vec<String> = image.words.iter().map(|word| format!("{:#x}", word)).collect();
+        format!("[{}]", words.join(", "))
+    }
+
+    #[test]
+    fn memory_sizes() {
+        let device = "{id: box, name: box, description: A box., program: [0x13, 0x13]";
+        assert!(decode(&format!("{}}}", device)).is_ok());
+        assert!(decode(&format!("{}, memory_words: 2}}", device)).is_ok());
+        assert!(decode(&format!("{}, memory_words: 1}}", device)).is_err());
+        assert!(decode(&format!("{}, memory_words: 0}}", device)).is_err());
+        assert!(decode(&format!("{}, memory_words: -4}}", device)).is_err());
+
+        // Sensors and actuators must be in memory too
+        assert!(decode(&format!("{}, memory_words: 4, sensors: {{0xc: time}}}}", device)).is_ok());
+        assert!(decode(&format!("{}, memory_words: 4, sensors: {{0x10: time}}}}", device)).is_err());
+        assert!(decode(&format!("{}, memory_words: 4, actuators: {{0x10: {{flag: f}}}}}}", device)).is_err());
+    }
+
+    #[test]
+    fn sensors_and_actuators() {
+        let mut device = decode(&format!("
+id: door
+name: door
+description: A door.
+program: {}
+sensors:
+  0x100: {{item: visa}}
+actuators:
+  0x104: {{flag: open}}
+", program("loop: lw t0, 0x100(zero)\nsw t0, 0x104(zero)\njal zero, loop"))).unwrap();
+
+        let mut player = Player::new("Ana");
+        let mut flags = HashSet::new();
+        let time = chrono::UTC.ymd(2048, 1, 2).and_hms(7, 7, 0);
+        device.run(&player, &mut flags, &time);
+        assert!(!flags.contains("open"));
+
+        player.inventory.push("visa".to_owned());
+        device.run(&player, &mut flags, &time);
+        assert!(flags.contains("open"));
+
+        player.inventory.clear();
+        device.run(&player, &mut flags, &time);
+        assert!(!flags.contains("open"));
+    }
+
+    #[test]
+    fn reprogramming_clears_memory() {
+        // Sets the flag, then traps on the zero word after it
+        let mut device = decode(&format!("
+id: latch
+name: latch
+description: A latch.
+program: {}
+actuators:
+  0x104: {{flag: open}}
+", program("addi t0, zero, 1\nsw t0, 0x104(zero)"))).unwrap();
+
+        let player = Player::new("Ana");
+        let mut flags = HashSet::new();
+        let time = chrono::UTC.ymd(2048, 1, 2).and_hms(7, 7, 0);
+        device.run(&player, &mut flags, &time);
+        assert!(flags.contains("open"));
+
+        device.reprogram(vec![]).unwrap();
+        device.run(&player, &mut flags, &time);
+        assert!(!flags.contains("open"));
+        assert!(device.reprogram(vec![0x13; 129]).is_err());
+        assert!(device.program.is_empty());
+    }
+}
@@ -0,0 +1,309 @@
+use std::collections::{HashMap, HashSet};
+
+use yaml_rust;
+
+use assets;
+use dice;
+use player::{Attribute, Player};
+
+pub enum Condition {
+    Flag(String),
+    NotFlag(String),
+    HasItem(String),
+    Credits(u32),
+    // attribute : minimum value
+    Attribute(Attribute, i32),
+}
+
+impl Condition {
+    pub fn holds(&self, player: &Player, flags: &HashSet<String>) -> bool {
+        match *self {
+            Condition::Flag(ref flag) => flags.contains(flag),
+            Condition::NotFlag(ref flag) => !flags.contains(flag),
+            Condition::HasItem(ref id) => player.inventory.contains(id),
+            Condition::Credits(amount) => player.credits >= amount,
+            Condition::Attribute(attr, min) => player.attribute(attr) >= min,
+        }
+    }
+}
+
+pub enum Effect {
+    SetFlag(String),
+    ClearFlag(String),
+    GiveItem(String),
+    TakeItem(String),
+    Credits(i64),
+}
+
+pub struct Choice {
+    pub text: String,
+    pub conditions: Vec<Condition>,
+    pub effects: Vec<Effect>,
+    // None ends the conversation
+    pub next: Option<String>,
+    // If the check fails, effects are skipped and the conversation
+    // moves to `failure` instead
+    pub check: Option<dice::SkillCheck>,
+    pub failure: Option<String>,
+}
+
+impl Choice {
+    pub fn is_available(&self, player: &Player, flags: &HashSet<String>) -> bool {
+        self.conditions.iter().all(|c| c.holds(player, flags))
+    }
+}
+
+pub struct Node {
+    pub lines: Vec<String>,
+    pub choices: Vec<Choice>,
+}
+
+pub struct Dialogue {
+    pub start: String,
+    pub nodes: HashMap<String, Node>,
+}
+
+impl Dialogue {
+    pub fn node(&self, id: &str) -> Option<&Node> {
+        self.nodes.get(id)
+    }
+}
+
+// Conditions and effects are written as single-entry hashes, like
+// `{has_item: pen}` or `{credits: -10}`.
+fn single_entry(doc: &yaml_rust::Yaml) -> assets::Result<(&str, &yaml_rust::Yaml)> {
+    let hash = as_value!(doc, as_hash);
+    if hash.len() != 1 {
+        return Err(assets::DecodeError::TypeError(format!("Expected a single key, found {}", hash.len())));
+    }
+    let (key, value) = hash.iter().next().unwrap();
+    Ok((as_value!(key, as_str), value))
+}
+
+impl assets::Decodable for Condition {
+    fn decode(doc: &yaml_rust::Yaml) -> assets::Result<Condition> {
+        let (kind, value) = try!(single_entry(doc));
+        Ok(match kind {
+            "flag" => Condition::Flag(as_value!(value, as_str).to_owned()),
+            "not_flag" => Condition::NotFlag(as_value!(value, as_str).to_owned()),
+            "has_item" => Condition::HasItem(as_value!(value, as_str).to_owned()),
+            "credits" => Condition::Credits(as_value!(value, as_i64) as u32),
+            _ => match Attribute::from_name(kind) {
+                Some(attr) => Condition::Attribute(attr, as_value!(value, as_i64) as i32),
+                None => return Err(assets::DecodeError::TypeError(format!("Unknown condition {}", kind))),
+            },
+        })
+    }
+}
+
+impl assets::Decodable for Effect {
+    fn decode(doc: &yaml_rust::Yaml) -> assets::Result<Effect> {
+        let (kind, value) = try!(single_entry(doc));
+        Ok(match kind {
+            "set_flag" => Effect::SetFlag(as_value!(value, as_str).to_owned()),
+            "clear_flag" => Effect::ClearFlag(as_value!(value, as_str).to_owned()),
+            "give_item" => Effect::GiveItem(as_value!(value, as_str).to_owned()),
+            "take_item" => Effect::TakeItem(as_value!(value, as_str).to_owned()),
+            "credits" => Effect::Credits(as_value!(value, as_i64)),
+            _ => return Err(assets::DecodeError::TypeError(format!("Unknown effect {}", kind))),
+        })
+    }
+}
+
+fn decode_list<T: assets::Decodable>(doc: &yaml_rust::Yaml, field: &str) -> assets::Result<Vec<T>> {
+    let mut result = vec![];
+    if let Some(entries) = get_optional_field!(doc, field, as_vec) {
+        for entry in entries.iter() {
+            result.push(try!(T::decode(entry)));
+        }
+    }
+    Ok(result)
+}
+
+impl assets::Decodable for Choice {
+    fn decode(doc: &yaml_rust::Yaml) -> assets::Result<Choice> {
+        let text = get_field!(doc, "text", as_str);
+        let next = get_optional_field!(doc, "next", as_str);
+        let failure = get_optional_field!(doc, "failure", as_str);
+        let check = if doc["check"].is_badvalue() {
+            None
+        }
+        else {
+            Some(try!(assets::Decodable::decode(&doc["check"])))
+        };
+
+        Ok(Choice {
+            text: text.to_owned(),
+            conditions: try!(decode_list(doc, "conditions")),
+            effects: try!(decode_list(doc, "effects")),
+            next: next.map(|n| n.to_owned()),
+            check: check,
+            failure: failure.map(|n| n.to_owned()),
+        })
+    }
+}
+
+impl assets::Decodable for Node {
+    fn decode(doc: &yaml_rust::Yaml) -> assets::Result<Node> {
+        let mut lines = vec![];
+        for line in get_field!(doc, "lines", as_vec).iter() {
+            lines.push(as_value!(line, as_str).to_owned());
+        }
+
+        Ok(Node {
+            lines: lines,
+            choices: try!(decode_list(doc, "choices")),
+        })
+    }
+}
+
+impl assets::Decodable for Dialogue {
+    fn decode(doc: &yaml_rust::Yaml) -> assets::Result<Dialogue> {
+        let start = get_field!(doc, "start", as_str);
+
+        let mut nodes = HashMap::new();
+        for (id, node) in get_field!(doc, "nodes", as_hash).iter() {
+            nodes.insert(as_value!(id, as_str).to_owned(), try!(assets::Decodable::decode(node)));
+        }
+
+        Ok(Dialogue {
+            start: start.to_owned(),
+            nodes: nodes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use yaml_rust::{Yaml, YamlLoader};
+
+    use assets::Decodable;
+    use player::{Attribute, Player};
+
+    use super::{Condition, Dialogue, Effect, Node};
+
+    const DIALOGUE: &'static str = "
+start: gate
+nodes:
+  gate:
+    lines: ['Who goes there?']
+    choices:
+      - text: Open up, it's me.
+        conditions: [{flag: known}]
+        next: inside
+      - text: A friend.
+        conditions: [{not_flag: known}]
+        effects: [{set_flag: known}]
+        next: bribe
+      - text: (Leave.)
+  bribe:
+    lines: ['Friends pay the toll.']
+    choices:
+      - text: (Pay 10 credits.)
+        conditions: [{credits: 10}]
+        effects: [{credits: -10}]
+        next: inside
+      - text: (Show the badge.)
+        conditions: [{has_item: badge}, {charisma: 7}]
+        next: inside
+      - text: (Leave.)
+  inside:
+    lines: ['Go on through.', 'Mind the dog.']
+";
+
+    fn load(source: &str) -> Vec<Yaml> {
+        YamlLoader::load_from_str(source).unwrap()
+    }
+
+    fn available<'a>(node: &'a Node, player: &Player, flags: &HashSet<String>) -> Vec<&'a str> {
+        node.choices.iter()
+            .filter(|choice| choice.is_available(player, flags))
+            .map(|choice| &choice.text[..])
+            .collect()
+    }
+
+    #[test]
+    fn conditions() {
+        let docs = load("[{flag: f}, {not_flag: f}, {has_item: pen}, {credits: 10}, {INT: 7}, {charisma: 3}]");
+        let conditions: Vec<Condition> = docs[0].as_vec().unwrap().iter()
+            .map(|doc| Condition::decode(doc).unwrap())
+            .collect();
+
+        let mut player = Player::new("Ana");
+        let mut flags = HashSet::new();
+        let holding = |player: &Player, flags: &HashSet<String>| -> Vec<bool> {
+            conditions.iter().map(|condition| condition.holds(player, flags)).collect()
+        };
+        assert_eq!(holding(&player, &flags), vec![false, true, false, false, false, true]);
+
+        flags.insert("f".to_owned());
+        player.inventory.push("pen".to_owned());
+        player.credits = 10;
+        player.set_attribute(Attribute::Intellect, 7);
+        player.set_attribute(Attribute::Charisma, 2);
+        assert_eq!(holding(&player, &flags), vec![true, false, true, true, true, false]);
+
+        for source in &["{luck: 3}", "{flag: a, not_flag: b}", "{}", "flag", "{flag: [a]}"] {
+            assert!(Condition::decode(&load(source)[0]).is_err(), "decoded {}", source);
+        }
+    }
+
+    #[test]
+    fn effects() {
+        let docs = load("[{set_flag: a}, {clear_flag: b}, {give_item: visa}, {take_item: pen}, {credits: -10}]");
+        let effects: Vec<Effect> = docs[0].as_vec().unwrap().iter()
+            .map(|doc| Effect::decode(doc).unwrap())
+            .collect();
+
+        let decoded: Vec<String> = effects.iter().map(|effect| match *effect {
+            Effect::SetFlag(ref flag) => format!("set {}", flag),
+            Effect::ClearFlag(ref flag) => format!("clear {}", flag),
+            Effect::GiveItem(ref id) => format!("give {}", id),
+            Effect::TakeItem(ref id) => format!("take {}", id),
+            Effect::Credits(amount) => format!("credits {}", amount),
+        }).collect();
+        assert_eq!(decoded, vec!["set a", "clear b", "give visa", "take pen", "credits -10"]);
+
+        for source in &["{set_flag: a, clear_flag: b}", "{teleport: roof}", "{credits: lots}"] {
+            assert!(Effect::decode(&load(source)[0]).is_err(), "decoded {}", source);
+        }
+    }
+
+    #[test]
+    fn branching() {
+        let dialogue = Dialogue::decode(&load(DIALOGUE)[0]).unwrap();
+        let mut player = Player::new("Ana");
+        let mut flags = HashSet::new();
+
+        let gate = dialogue.node(&dialogue.start).unwrap();
+        assert_eq!(gate.lines, vec!["Who goes there?"]);
+        // Only strangers can claim to be a friend
+        assert_eq!(available(gate, &player, &flags), vec!["A friend.", "(Leave.)"]);
+        assert!(gate.choices[2].next.is_none());
+
+        let friend = &gate.choices[1];
+        assert_eq!(friend.effects.len(), 1);
+        match friend.effects[0] {
+            Effect::SetFlag(ref flag) => flags.insert(flag.clone()),
+            _ => panic!("expected a set_flag effect"),
+        };
+        assert_eq!(available(gate, &player, &flags), vec!["Open up, it's me.", "(Leave.)"]);
+
+        // Both ways past the toll are hidden until their conditions hold
+        let bribe = dialogue.node(friend.next.as_ref().unwrap()).unwrap();
+        assert_eq!(available(bribe, &player, &flags), vec!["(Leave.)"]);
+        player.inventory.push("badge".to_owned());
+        assert_eq!(available(bribe, &player, &flags), vec!["(Leave.)"]);
+        player.set_attribute(Attribute::Charisma, 7);
+        assert_eq!(available(bribe, &player, &flags), vec!["(Show the badge.)", "(Leave.)"]);
+        player.credits = 10;
+        assert_eq!(available(bribe, &player, &flags), vec!["(Pay 10 credits.)", "(Show the badge.)", "(Leave.)"]);
+
+        let inside = dialogue.node(bribe.choices[0].next.as_ref().unwrap()).unwrap();
+        assert_eq!(inside.lines, vec!["Go on through.", "Mind the dog."]);
+        assert!(inside.choices.is_empty());
+        assert!(dialogue.node("nowhere").is_none());
+    }
+}
@@ -0,0 +1,185 @@
+use std::fmt;
+
+use yaml_rust;
+
+use assets;
+use player::{self, Attribute, Player};
+
+/// A small xorshift* generator. Rolls are reproducible for a given
+/// seed, which keeps playthroughs and transcripts deterministic.
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // xorshift gets stuck at zero
+        Rng {
+            state: if seed == 0 { 0x9E3779B97F4A7C15 } else { seed },
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    /// Rolls a die with the given number of sides, from 1 to `sides`.
+    pub fn roll(&mut self, sides: u32) -> u32 {
+        (self.next_u64() % sides as u64) as u32 + 1
+    }
+}
+
+/// The bonus (or penalty) an attribute adds to a d20 roll.
+pub fn modifier(score: i32) -> i32 {
+    score - player::BASE_ATTRIBUTE
+}
+
+#[derive(Clone)]
+pub struct SkillCheck {
+    pub attribute: Attribute,
+    pub difficulty: i32,
+}
+
+impl SkillCheck {
+    pub fn new(attribute: Attribute, difficulty: i32) -> SkillCheck {
+        SkillCheck {
+            attribute: attribute,
+            difficulty: difficulty,
+        }
+    }
+
+    pub fn roll(&self, player: &Player, rng: &mut Rng) -> CheckResult {
+        CheckResult {
+            attribute: self.attribute,
+            roll: rng.roll(20) as i32,
+            modifier: modifier(player.attribute(self.attribute)),
+            difficulty: self.difficulty,
+        }
+    }
+}
+
+impl assets::Decodable for SkillCheck {
+    fn decode(doc: &yaml_rust::Yaml) -> assets::Result<SkillCheck> {
+        let name = get_field!(doc, "attribute", as_str);
+        let attribute = try!(Attribute::from_name(name).ok_or(
+            assets::DecodeError::TypeError(format!("Unknown attribute {}", name))));
+
+        Ok(SkillCheck::new(attribute, get_field!(doc, "difficulty", as_i64) as i32))
+    }
+}
+
+pub struct CheckResult {
+    pub attribute: Attribute,
+    pub roll: i32,
+    pub modifier: i32,
+    pub difficulty: i32,
+}
+
+impl CheckResult {
+    pub fn total(&self) -> i32 {
+        self.roll + self.modifier
+    }
+
+    /// A natural 20 always succeeds and a natural 1 always fails.
+    pub fn success(&self) -> bool {
+        match self.roll {
+            20 => true,
+            1 => false,
+            _ => self.total() >= self.difficulty,
+        }
+    }
+}
+
+impl fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let sign = if self.modifier < 0 { "-" } else { "+" };
+        write!(f, "{} check: rolled {} {} {} = {} vs {}: {}",
+               self.attribute.abbreviation(),
+               self.roll, sign, self.modifier.abs(), self.total(),
+               self.difficulty,
+               if self.success() { "success" } else { "failure" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use player::{Attribute, Player};
+
+    use super::{modifier, CheckResult, Rng, SkillCheck};
+
+    fn result(roll: i32, modifier: i32, difficulty: i32) -> CheckResult {
+        CheckResult {
+            attribute: Attribute::Strength,
+            roll: roll,
+            modifier: modifier,
+            difficulty: difficulty,
+        }
+    }
+
+    #[test]
+    fn rolls() {
+        let mut rng = Rng::new(42);
+        let rolls: Vec<u32> = (0..1000).map(|_| rng.roll(20)).collect();
+        assert!(rolls.iter().all(|&roll| roll >= 1 && roll <= 20));
+        assert!(rolls.contains(&1) && rolls.contains(&20));
+
+        let mut again = Rng::new(42);
+        assert_eq!(rolls, (0..1000).map(|_| again.roll(20)).collect::<Vec<_>>());
+
+        // Zero is remapped rather than getting stuck
+        let mut zero = Rng::new(0);
+        assert!(zero.next_u64() != zero.next_u64());
+    }
+
+    #[test]
+    fn modifiers() {
+        assert_eq!(modifier(5), 0);
+        assert_eq!(modifier(8), 3);
+        assert_eq!(modifier(2), -3);
+
+        let mut player = Player::new("Ana");
+        player.set_attribute(Attribute::Intellect, 9);
+        let check = SkillCheck::new(Attribute::Intellect, 15);
+        let mut rng = Rng::new(7);
+        for _ in 0..100 {
+            let result = check.roll(&player, &mut rng);
+            assert_eq!(result.modifier, 4);
+            assert_eq!(result.total(), result.roll + 4);
+            assert_eq!(result.difficulty, 15);
+            assert_eq!(result.attribute, Attribute::Intellect);
+        }
+    }
+
+    #[test]
+    fn success() {
+        assert!(result(10, 4, 14).success());
+        assert!(!result(10, 3, 14).success());
+        assert!(result(15, -1, 14).success());
+        assert!(!result(15, -2, 14).success());
+    }
+
+    #[test]
+    fn natural_rolls() {
+        // A natural 1 fails even when the total beats the difficulty
+        let critical_failure = result(1, 20, 5);
+        assert_eq!(critical_failure.total(), 21);
+        assert!(!critical_failure.success());
+
+        // A natural 20 succeeds even when the total doesn't
+        let critical_success = result(20, -5, 30);
+        assert_eq!(critical_success.total(), 15);
+        assert!(critical_success.success());
+    }
+
+    #[test]
+    fn breakdown() {
+        assert_eq!(format!("{}", result(12, 3, 14)), "STR check: rolled 12 + 3 = 15 vs 14: success");
+        assert_eq!(format!("{}", result(12, -2, 14)), "STR check: rolled 12 - 2 = 10 vs 14: failure");
+        assert_eq!(format!("{}", result(7, 0, 7)), "STR check: rolled 7 + 0 = 7 vs 7: success");
+        assert_eq!(format!("{}", result(1, 20, 5)), "STR check: rolled 1 + 20 = 21 vs 5: failure");
+        assert_eq!(format!("{}", result(20, -5, 30)), "STR check: rolled 20 - 5 = 15 vs 30: success");
+    }
+}
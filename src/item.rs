@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+
+use yaml_rust;
+
+use assets;
+
+pub struct Item {
+    pub name: String,
+    pub description: String,
+    pub weight: u32,
+    pub tags: Vec<String>,
+    // Bonus damage when wielded (items tagged "weapon")
+    pub damage: i32,
+    // HP restored when used (items tagged "consumable")
+    pub heal: i32,
+    // Codes tried per second (items tagged "brute-force")
+    pub rate: u32,
+}
+
+impl Item {
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+}
+
+impl assets::Decodable for (String, Item) {
+    fn decode(doc: &yaml_rust::Yaml) -> assets::Result<(String, Item)> {
+        let id = get_field!(doc, "id", as_str);
+        let name = get_field!(doc, "name", as_str);
+        let desc = get_field!(doc, "description", as_str);
+        let weight = get_optional_field!(doc, "weight", as_i64).unwrap_or(0);
+
+        let mut tags = vec![];
+        if let Some(tag_list) = get_optional_field!(doc, "tags", as_vec) {
+            for tag in tag_list.iter() {
+                tags.push(as_value!(tag, as_str).to_owned());
+            }
+        }
+
+        Ok((id.to_owned(), Item {
+            name: name.to_owned(),
+            description: desc.to_owned(),
+            weight: weight as u32,
+            tags: tags,
+            damage: get_optional_field!(doc, "damage", as_i64).unwrap_or(0) as i32,
+            heal: get_optional_field!(doc, "heal", as_i64).unwrap_or(0) as i32,
+            rate: get_optional_field!(doc, "rate", as_i64).unwrap_or(0) as u32,
+        }))
+    }
+}
+
+pub fn load_items(docs: Vec<yaml_rust::Yaml>) -> assets::Result<HashMap<String, Item>> {
+    assets::load_table(docs)
+}
+
+/// Finds the position of the item in `ids` whose name or ID matches
+/// `name`, ignoring case.
+pub fn find_by_name(ids: &[String], items: &HashMap<String, Item>, name: &str) -> Option<usize> {
+    let name = name.to_lowercase();
+    ids.iter().position(|id| {
+        *id == name || items.get(id).map_or(false, |item| item.name.to_lowercase() == name)
+    })
+}
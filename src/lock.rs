@@ -0,0 +1,186 @@
+use yaml_rust;
+
+use assets;
+
+#[derive(Clone)]
+pub enum Lock {
+    // Opened by entering the code, or brute-forced by a key
+    Code(String),
+    // Opened by using the item (ID) on the door
+    Item(String),
+    // Open exactly when the world flag is set
+    Flag(String),
+}
+
+impl assets::Decodable for Lock {
+    fn decode(doc: &yaml_rust::Yaml) -> assets::Result<Lock> {
+        if let Some(code) = get_optional_field!(doc, "code", as_str) {
+            Ok(Lock::Code(code.to_owned()))
+        }
+        else if let Some(item) = get_optional_field!(doc, "item", as_str) {
+            Ok(Lock::Item(item.to_owned()))
+        }
+        else if let Some(flag) = get_optional_field!(doc, "flag", as_str) {
+            Ok(Lock::Flag(flag.to_owned()))
+        }
+        else {
+            Err(assets::DecodeError::TypeError(format!("Lock needs a code, item or flag")))
+        }
+    }
+}
+
+/// How many codes a key trying every code in order, starting from all
+/// zeroes, needs to find this one. Only numeric codes can be
+/// brute-forced.
+pub fn brute_force_attempts(code: &str) -> Option<u64> {
+    if code.is_empty() || !code.chars().all(|c| c.is_digit(10)) {
+        return None;
+    }
+    code.parse::<u64>().ok().map(|n| n + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use chrono;
+    use yaml_rust::YamlLoader;
+
+    use assets::Decodable;
+    use room;
+    use state::Game;
+    use ui::transcript::{Recording, TranscriptFrontend};
+
+    use super::{brute_force_attempts, Lock};
+
+    const VAULT: &'static str = "
+id: vault
+name: Vault
+description: A heavy vault door blocks the way.
+exits:
+  border_office_1:
+    phrases: [vault door]
+    lock: {item: visa}
+contents: [visa]
+";
+
+    fn new_game(room: &str, inputs: &[&str]) -> (Game, Recording) {
+        let recording: Recording = Rc::new(RefCell::new(vec![]));
+        let inputs = inputs.iter().map(|&input| input.to_owned()).collect();
+        let frontend = TranscriptFrontend::new(inputs, recording.clone());
+
+        let mut world = ::load_world("rooms.yaml");
+        let docs = YamlLoader::load_from_str(VAULT).unwrap();
+        let (id, vault): (String, room::Room) = Decodable::decode(&docs[0]).unwrap();
+        world.add_room(id, vault);
+
+        let mut game = Game::new(world, 1, Box::new(frontend));
+        game.room = room.to_owned();
+        (game, recording)
+    }
+
+    fn printed(recording: &Recording, line: &str) -> bool {
+        recording.borrow().iter().any(|lines| lines.iter().any(|printed| printed == line))
+    }
+
+    #[test]
+    fn decode() {
+        let docs = YamlLoader::load_from_str("{code: '0451'}\n---\n{item: visa}\n---\n{flag: lift_enabled}\n---\n{}")
+            .unwrap();
+        match Lock::decode(&docs[0]) {
+            Ok(Lock::Code(ref code)) => assert_eq!(code, "0451"),
+            _ => panic!("expected a code lock"),
+        }
+        match Lock::decode(&docs[1]) {
+            Ok(Lock::Item(ref item)) => assert_eq!(item, "visa"),
+            _ => panic!("expected an item lock"),
+        }
+        match Lock::decode(&docs[2]) {
+            Ok(Lock::Flag(ref flag)) => assert_eq!(flag, "lift_enabled"),
+            _ => panic!("expected a flag lock"),
+        }
+        assert!(Lock::decode(&docs[3]).is_err());
+    }
+
+    #[test]
+    fn code_locks() {
+        let (mut game, recording) = new_game("arrivals_hall", &[
+            "unlock maintenance door", "1234",
+            "unlock maintenance door", "0451",
+            "unlock maintenance door",
+        ]);
+        let start = game.time;
+        game.main().unwrap();
+
+        assert!(printed(&recording, "The keypad buzzes angrily."));
+        assert!(printed(&recording, "The maintenance door unlocks."));
+        assert!(printed(&recording, "The maintenance door isn't locked."));
+        assert!(game.world.flags.contains("unlocked_arrivals_hall_maintenance_corridor"));
+        // Each attempt at the keypad takes 5 seconds
+        assert_eq!(game.time, start + chrono::Duration::seconds(10));
+    }
+
+    #[test]
+    fn item_locks() {
+        let (mut game, recording) = new_game("vault", &[
+            "unlock vault door",
+            "use visa on vault door",
+            "take visa",
+            "unlock vault door",
+        ]);
+        game.main().unwrap();
+
+        assert!(printed(&recording, "You need a [visa]."));
+        assert!(printed(&recording, "You aren't carrying a visa."));
+        assert!(printed(&recording, "You use the [visa]."));
+        assert!(printed(&recording, "The vault door unlocks."));
+        assert!(game.world.flags.contains("unlocked_vault_border_office_1"));
+
+        let (mut game, _) = new_game("vault", &["take visa", "use visa on vault door"]);
+        game.main().unwrap();
+        assert!(game.world.flags.contains("unlocked_vault_border_office_1"));
+    }
+
+    #[test]
+    fn flag_locks() {
+        let (mut game, recording) = new_game("maintenance_corridor", &["unlock lift", "go lift"]);
+        game.main().unwrap();
+        assert!(printed(&recording, "The lift won't budge."));
+        assert!(printed(&recording, "The service lift's call button is dead."));
+        assert_eq!(game.room, "maintenance_corridor");
+
+        // The lift controller clears the flag again after every command
+        let (mut game, recording) = new_game("maintenance_corridor", &["unlock lift"]);
+        game.world.flags.insert("lift_enabled".to_owned());
+        game.main().unwrap();
+        assert!(printed(&recording, "The lift isn't locked."));
+        assert!(!game.world.flags.contains("lift_enabled"));
+
+        let (mut game, _) = new_game("maintenance_corridor", &["go lift"]);
+        game.world.flags.insert("lift_enabled".to_owned());
+        game.main().unwrap();
+        assert_eq!(game.room, "rooftop");
+    }
+
+    #[test]
+    fn brute_force() {
+        assert_eq!(brute_force_attempts("0000"), Some(1));
+        assert_eq!(brute_force_attempts("0451"), Some(452));
+        assert_eq!(brute_force_attempts("9999"), Some(10000));
+        assert_eq!(brute_force_attempts(""), None);
+        assert_eq!(brute_force_attempts("04a1"), None);
+        assert_eq!(brute_force_attempts("-451"), None);
+
+        // 452 codes at 50 codes a second
+        let (mut game, recording) = new_game("arrivals_hall", &["use cracker on maintenance door"]);
+        game.player.inventory.push("cracker".to_owned());
+        let start = game.time;
+        game.main().unwrap();
+
+        assert!(printed(&recording, "0112... 0113..."));
+        assert!(printed(&recording, "0451. Click. (452 codes in 9 seconds)"));
+        assert!(game.world.flags.contains("unlocked_arrivals_hall_maintenance_corridor"));
+        assert_eq!(game.time, start + chrono::Duration::seconds(9));
+    }
+}
@@ -1,44 +1,115 @@
 #![feature(braced_empty_structs)]
 extern crate chrono;
 extern crate ncurses;
+extern crate riscvisualizer;
 extern crate yaml_rust;
 
 #[macro_use]
 mod assets;
+mod combat;
+mod creation;
+mod device;
+mod dialogue;
+mod dice;
+mod item;
+mod lock;
+mod npc;
 mod player;
 mod room;
+mod save;
 mod state;
+mod transcript;
 mod ui;
 
-use std::io::Read;
+use std::env;
+use std::process;
+use std::io::{self, Read, Write};
 use std::fs::File;
 use std::path::Path;
 
-use ncurses::*;
+fn load_yaml(path: &str) -> Vec<yaml_rust::Yaml> {
+    let mut contents = String::new();
+    let mut file = File::open(&Path::new(path)).unwrap();
+    file.read_to_string(&mut contents).unwrap();
 
-fn main() {
-    let locale_conf = LcCategory::all;
-    setlocale(locale_conf, "en_US.UTF-8");
-    initscr();
-    raw();
-    nonl();
-    keypad(stdscr, true);
-    noecho();
-    curs_set(CURSOR_VISIBILITY::CURSOR_INVISIBLE);
+    yaml_rust::YamlLoader::load_from_str(&contents).unwrap()
+}
+
+const START_ROOM: &'static str = "border_office_1";
+
+fn load_world(rooms_path: &str) -> state::World {
+    let rooms = room::load_rooms(load_yaml(rooms_path)).unwrap();
+    let items = item::load_items(load_yaml("items.yaml")).unwrap();
+    let npcs = npc::load_npcs(load_yaml("npcs.yaml")).unwrap();
+    let devices = device::load_devices(load_yaml("devices.yaml")).unwrap();
+
+    state::World::new(rooms, items, npcs, devices)
+}
 
-    refresh();
+/// Plays a transcript and prints any mismatches. Exits with status 1
+/// if there were any.
+fn run_transcript(path: &str) {
+    let transcript = match transcript::Transcript::load(path) {
+        Ok(transcript) => transcript,
+        Err(err) => {
+            writeln!(io::stderr(), "{}", err).unwrap();
+            process::exit(2);
+        }
+    };
+    let world = load_world(transcript.rooms.as_ref().map_or("rooms.yaml", |rooms| &rooms[..]));
+    let failures = transcript.run(world, transcript.start.as_ref().map_or(START_ROOM, |start| &start[..]));
 
-    let mut room_contents = String::new();
-    let mut file = File::open(&Path::new("rooms.yaml")).unwrap();
-    file.read_to_string(&mut room_contents).unwrap();
+    for failure in failures.iter() {
+        writeln!(io::stderr(), "{}", failure).unwrap();
+    }
+    if failures.is_empty() {
+        println!("{}: ok", path);
+    }
+    else {
+        writeln!(io::stderr(), "{}: {} failure(s)", path, failures.len()).unwrap();
+        process::exit(1);
+    }
+}
 
-    let docs = yaml_rust::YamlLoader::load_from_str(&room_contents).unwrap();
-    let rooms = room::load_rooms(docs).unwrap();
+fn main() {
+    // Pass --seed N to replay the same dice rolls, --load SLOT to
+    // continue a saved game, --rooms FILE to play different rooms, and
+    // --plain to play on stdin/stdout without curses. --transcript FILE
+    // replays and checks a transcript instead (see transcript.rs)
+    let args: Vec<String> = env::args().collect();
+    let option = |name: &str| {
+        args.iter().position(|arg| arg == name).and_then(|i| args.get(i + 1))
+    };
+    if let Some(path) = option("--transcript") {
+        run_transcript(path);
+        return;
+    }
 
-    let mut game = state::Game::new(state::World::new(rooms));
+    let seed = option("--seed")
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or(chrono::UTC::now().timestamp() as u64);
+    let load_slot = option("--load");
+    let frontend: Box<ui::Frontend> = if args.iter().any(|arg| arg == "--plain") {
+        Box::new(ui::stdio::StdioFrontend::new())
+    }
+    else {
+        Box::new(ui::layout::UILayout::new())
+    };
+    let world = load_world(option("--rooms").map_or("rooms.yaml", |rooms| &rooms[..]));
 
-    game.enter_room("border_office_1").unwrap();
-    game.main();
+    let mut game = state::Game::new(world, seed, frontend);
+
+    let result = game.start(START_ROOM, load_slot.map(|slot| &slot[..])).and_then(|()| game.main());
+    // Leave curses before reporting anything
+    drop(game);
+    match result {
+        Err(ref err) if err.is_end_of_input() => (),
+        Err(err) => {
+            writeln!(io::stderr(), "{}", err).unwrap();
+            process::exit(1);
+        }
+        Ok(()) => (),
+    }
 
     // output.append("ACT I—HELLO WORLD");
     // output.append("=================");
@@ -47,6 +118,4 @@ fn main() {
 
 
     // title_win.print(0, &format!("Location: {:20} 17:07:17 MON 25 MAR 2048", cur_room.name));
-
-    endwin();
 }
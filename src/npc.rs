@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+
+use yaml_rust;
+
+use assets;
+use combat;
+use dialogue;
+
+pub struct Npc {
+    pub name: String,
+    pub description: String,
+    pub dialogue: Option<dialogue::Dialogue>,
+    // Hostile NPCs start a fight as soon as the player walks in
+    pub hostile: bool,
+    pub combat: Option<combat::CombatStats>,
+}
+
+impl assets::Decodable for (String, Npc) {
+    fn decode(doc: &yaml_rust::Yaml) -> assets::Result<(String, Npc)> {
+        let id = get_field!(doc, "id", as_str);
+        let name = get_field!(doc, "name", as_str);
+        let desc = get_field!(doc, "description", as_str);
+
+        let dialogue = if doc["dialogue"].is_badvalue() {
+            None
+        }
+        else {
+            Some(try!(assets::Decodable::decode(&doc["dialogue"])))
+        };
+
+        let combat = if doc["combat"].is_badvalue() {
+            None
+        }
+        else {
+            Some(try!(assets::Decodable::decode(&doc["combat"])))
+        };
+
+        Ok((id.to_owned(), Npc {
+            name: name.to_owned(),
+            description: desc.to_owned(),
+            dialogue: dialogue,
+            hostile: get_optional_field!(doc, "hostile", as_bool).unwrap_or(false),
+            combat: combat,
+        }))
+    }
+}
+
+pub fn load_npcs(docs: Vec<yaml_rust::Yaml>) -> assets::Result<HashMap<String, Npc>> {
+    assets::load_table(docs)
+}
+
+/// Finds the position of the NPC in `ids` whose name or ID matches
+/// `name`, ignoring case.
+pub fn find_by_name(ids: &[String], npcs: &HashMap<String, Npc>, name: &str) -> Option<usize> {
+    let name = name.to_lowercase();
+    ids.iter().position(|id| {
+        *id == name || npcs.get(id).map_or(false, |npc| npc.name.to_lowercase() == name)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use state::Game;
+    use ui::transcript::{Recording, TranscriptFrontend};
+
+    use super::{find_by_name, load_npcs};
+
+    fn talk_to_clerk(inputs: &[&str], inventory: &[&str]) -> (Game, Vec<Vec<String>>) {
+        let recording: Recording = Rc::new(RefCell::new(vec![]));
+        let inputs = inputs.iter().map(|&input| input.to_owned()).collect();
+        let frontend = TranscriptFrontend::new(inputs, recording.clone());
+
+        let mut game = Game::new(::load_world("rooms.yaml"), 1, Box::new(frontend));
+        game.room = "border_office_2".to_owned();
+        game.player.inventory = inventory.iter().map(|&id| id.to_owned()).collect();
+        game.main().unwrap();
+
+        let output = recording.borrow().clone();
+        (game, output)
+    }
+
+    #[test]
+    fn decode() {
+        let world = ::load_world("rooms.yaml");
+        let clerk = world.get_npc("clerk").unwrap();
+        assert_eq!(clerk.name, "Clerk Okafor");
+        assert!(!clerk.hostile && clerk.combat.is_none());
+        let dialogue = clerk.dialogue.as_ref().unwrap();
+        assert_eq!(dialogue.start, "greeting");
+        assert_eq!(dialogue.nodes.len(), 4);
+
+        let pickpocket = world.get_npc("pickpocket").unwrap();
+        assert!(pickpocket.hostile && pickpocket.dialogue.is_none());
+        let stats = pickpocket.combat.as_ref().unwrap();
+        assert_eq!((stats.hp, stats.attack, stats.defense, stats.reflexes), (30, 14, 2, 6));
+        assert_eq!(stats.loot, vec!["knife", "cracker"]);
+    }
+
+    #[test]
+    fn find() {
+        let npcs = load_npcs(::load_yaml("npcs.yaml")).unwrap();
+        let ids = vec!["pickpocket".to_owned(), "clerk".to_owned()];
+        assert_eq!(find_by_name(&ids, &npcs, "Clerk Okafor"), Some(1));
+        assert_eq!(find_by_name(&ids, &npcs, "clerk"), Some(1));
+        assert_eq!(find_by_name(&ids, &npcs, "PICKPOCKET"), Some(0));
+        assert_eq!(find_by_name(&ids, &npcs, "okafor"), None);
+        assert_eq!(find_by_name(&ids[..1], &npcs, "clerk"), None);
+    }
+
+    #[test]
+    fn conversation() {
+        // The pen unlocks signing; the choices for a signed form stay
+        // hidden until then
+        let (game, output) = talk_to_clerk(&["talk to clerk", "1", "1", "talk to clerk", "1"], &["pen"]);
+        assert_eq!(&output[1][..3], &["Clerk Okafor: Next. Papers?", "[1] Here you are.", "[2] Never mind."][..]);
+        assert_eq!(&output[2][..4], &[
+            "Clerk Okafor: Fill this out. Sign at the bottom.",
+            "Clerk Okafor: We're out of pens, so I hope you brought your own.",
+            "[1] (Sign with the pen.)",
+            "[2] Surely a clerk of your standing has a spare.",
+        ][..]);
+        assert!(output[3].contains(&"Clerk Okafor: Welcome to Neo-Seattle. Keep your visa on you at all times."
+                                    .to_owned()));
+        assert_eq!(&output[4][..3], &[
+            "Clerk Okafor: Next. Papers?",
+            "[1] Already done. Can I go?",
+            "[2] Never mind.",
+        ][..]);
+        assert!(game.world.flags.contains("signed_entry_form"));
+        assert_eq!(game.player.inventory, vec!["pen", "visa"]);
+
+        // Without the pen, the first choice is the charisma check
+        let (game, output) = talk_to_clerk(&["talk to clerk", "1", "2"], &[]);
+        assert_eq!(&output[2][2..4], &["[1] Surely a clerk of your standing has a spare.", "[2] I'll be back."][..]);
+        assert!(!game.world.flags.contains("signed_entry_form"));
+        assert!(game.player.inventory.is_empty());
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+
+use yaml_rust::Yaml;
+
+use assets;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Attribute {
+    Strength,
+    Reflexes,
+    Intellect,
+    Charisma,
+}
+
+impl Attribute {
+    pub fn all() -> &'static [Attribute] {
+        static ALL: [Attribute; 4] = [
+            Attribute::Strength,
+            Attribute::Reflexes,
+            Attribute::Intellect,
+            Attribute::Charisma,
+        ];
+        &ALL
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Attribute::Strength => "strength",
+            Attribute::Reflexes => "reflexes",
+            Attribute::Intellect => "intellect",
+            Attribute::Charisma => "charisma",
+        }
+    }
+
+    pub fn abbreviation(&self) -> &'static str {
+        match *self {
+            Attribute::Strength => "STR",
+            Attribute::Reflexes => "REF",
+            Attribute::Intellect => "INT",
+            Attribute::Charisma => "CHA",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Attribute> {
+        let name = name.to_lowercase();
+        Attribute::all().iter().cloned().find(|attr| {
+            attr.name() == name || attr.abbreviation().to_lowercase() == name
+        })
+    }
+}
+
+/// A stat with a current value that is capped by a maximum, like HP.
+#[derive(Clone, Debug)]
+pub struct Stat {
+    pub current: i32,
+    pub max: i32,
+}
+
+impl Stat {
+    pub fn new(max: i32) -> Stat {
+        Stat {
+            current: max,
+            max: max,
+        }
+    }
+
+    pub fn reduce(&mut self, amount: i32) {
+        self.current = ::std::cmp::max(0, self.current - amount);
+    }
+
+    pub fn restore(&mut self, amount: i32) {
+        self.current = ::std::cmp::min(self.max, self.current + amount);
+    }
+
+    pub fn is_depleted(&self) -> bool {
+        self.current <= 0
+    }
+}
+
+impl assets::Encodable for Stat {
+    fn encode(&self) -> Yaml {
+        Yaml::Array(vec![Yaml::Integer(self.current as i64), Yaml::Integer(self.max as i64)])
+    }
+}
+
+impl assets::Decodable for Stat {
+    fn decode(doc: &Yaml) -> assets::Result<Stat> {
+        Ok(Stat {
+            current: as_value!(doc[0], as_i64) as i32,
+            max: as_value!(doc[1], as_i64) as i32,
+        })
+    }
+}
+
+pub const BASE_ATTRIBUTE: i32 = 5;
+
+pub struct Player {
+    pub name: String,
+    pub hp: Stat,
+    pub cpu: Stat,
+    pub credits: u32,
+    // item IDs
+    pub inventory: Vec<String>,
+    attributes: HashMap<Attribute, i32>,
+}
+
+impl Player {
+    pub fn new<S: Into<String>>(name: S) -> Player {
+        let mut attributes = HashMap::new();
+        for attr in Attribute::all() {
+            attributes.insert(*attr, BASE_ATTRIBUTE);
+        }
+
+        Player {
+            name: name.into(),
+            hp: Stat::new(100),
+            cpu: Stat::new(100),
+            credits: 0,
+            inventory: vec![],
+            attributes: attributes,
+        }
+    }
+
+    pub fn attribute(&self, attr: Attribute) -> i32 {
+        *self.attributes.get(&attr).unwrap_or(&BASE_ATTRIBUTE)
+    }
+
+    pub fn set_attribute(&mut self, attr: Attribute, value: i32) {
+        self.attributes.insert(attr, value);
+    }
+
+    /// The total item weight the player can carry.
+    pub fn carry_capacity(&self) -> u32 {
+        (10 + 2 * self.attribute(Attribute::Strength)) as u32
+    }
+
+    pub fn status_lines(&self) -> Vec<String> {
+        let mut lines = vec![
+            self.name.clone(),
+            format!("HP  {:>4}/{}", self.hp.current, self.hp.max),
+            format!("CPU {:>4}/{}", self.cpu.current, self.cpu.max),
+            format!("CR  {:>4}", self.credits),
+            "".to_owned(),
+        ];
+
+        for attr in Attribute::all() {
+            lines.push(format!("{} {:>4}", attr.abbreviation(), self.attribute(*attr)));
+        }
+
+        lines
+    }
+}
+
+impl assets::Encodable for Player {
+    fn encode(&self) -> Yaml {
+        let attributes = Attribute::all().iter()
+            .map(|attr| (attr.name(), Yaml::Integer(self.attribute(*attr) as i64)))
+            .collect();
+
+        assets::hash(vec![
+            ("name", Yaml::String(self.name.clone())),
+            ("hp", assets::Encodable::encode(&self.hp)),
+            ("cpu", assets::Encodable::encode(&self.cpu)),
+            ("credits", Yaml::Integer(self.credits as i64)),
+            ("inventory", assets::strings(&self.inventory)),
+            ("attributes", assets::hash(attributes)),
+        ])
+    }
+}
+
+impl assets::Decodable for Player {
+    fn decode(doc: &Yaml) -> assets::Result<Player> {
+        let mut player = Player::new(get_field!(doc, "name", as_str));
+        player.hp = try!(assets::Decodable::decode(&doc["hp"]));
+        player.cpu = try!(assets::Decodable::decode(&doc["cpu"]));
+        player.credits = get_field!(doc, "credits", as_i64) as u32;
+        player.inventory = try!(assets::decode_strings(&doc["inventory"]));
+
+        for attr in Attribute::all() {
+            let value = get_field!(doc["attributes"], attr.name(), as_i64);
+            player.set_attribute(*attr, value as i32);
+        }
+
+        Ok(player)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assets::{Decodable, Encodable};
+
+    use super::{Attribute, Player, Stat, BASE_ATTRIBUTE};
+
+    #[test]
+    fn round_trip() {
+        let mut player = Player::new("Ana");
+        player.hp.reduce(40);
+        player.cpu = Stat { current: 15, max: 120 };
+        player.credits = 250;
+        player.inventory = vec!["pen".to_owned(), "visa".to_owned(), "pen".to_owned()];
+        player.set_attribute(Attribute::Strength, 3);
+        player.set_attribute(Attribute::Charisma, 11);
+
+        let decoded = Player::decode(&player.encode()).unwrap();
+        assert_eq!(decoded.name, "Ana");
+        assert_eq!((decoded.hp.current, decoded.hp.max), (60, 100));
+        assert_eq!((decoded.cpu.current, decoded.cpu.max), (15, 120));
+        assert_eq!(decoded.credits, 250);
+        assert_eq!(decoded.inventory, player.inventory);
+        for attr in Attribute::all() {
+            assert_eq!(decoded.attribute(*attr), player.attribute(*attr), "{}", attr.name());
+        }
+        assert_eq!(decoded.status_lines(), player.status_lines());
+    }
+
+    #[test]
+    fn stat_bounds() {
+        let mut player = Player::new("Ana");
+        player.hp.reduce(30);
+        assert_eq!(player.hp.current, 70);
+        player.hp.restore(20);
+        assert_eq!(player.hp.current, 90);
+        // Restoring stops at the maximum
+        player.hp.restore(50);
+        assert_eq!(player.hp.current, 100);
+        assert!(!player.hp.is_depleted());
+        // Reducing stops at zero
+        player.hp.reduce(250);
+        assert_eq!(player.hp.current, 0);
+        assert!(player.hp.is_depleted());
+
+        player.cpu.reduce(100);
+        assert!(player.cpu.is_depleted());
+        player.cpu.restore(35);
+        assert_eq!((player.cpu.current, player.cpu.max), (35, 100));
+        player.cpu.reduce(36);
+        assert_eq!(player.cpu.current, 0);
+    }
+
+    #[test]
+    fn attributes() {
+        let mut player = Player::new("Ana");
+        for attr in Attribute::all() {
+            assert_eq!(player.attribute(*attr), BASE_ATTRIBUTE);
+            assert_eq!(Attribute::from_name(attr.name()), Some(*attr));
+            assert_eq!(Attribute::from_name(attr.abbreviation()), Some(*attr));
+        }
+        assert_eq!(Attribute::from_name("Reflexes"), Some(Attribute::Reflexes));
+        assert_eq!(Attribute::from_name("luck"), None);
+
+        assert_eq!(player.carry_capacity(), 20);
+        player.set_attribute(Attribute::Strength, 8);
+        assert_eq!(player.carry_capacity(), 26);
+    }
+}
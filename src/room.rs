@@ -3,25 +3,84 @@ use std::collections::HashMap;
 use yaml_rust;
 
 use assets;
+use dice;
+use lock;
+use state;
+
+#[derive(Clone)]
+pub struct Door {
+    // room ID
+    pub room: String,
+    // must be passed every time the door is used
+    pub check: Option<dice::SkillCheck>,
+    pub lock: Option<lock::Lock>,
+    // shown instead of the default when the door is locked
+    pub locked_message: Option<String>,
+}
 
 pub struct Room {
     pub name: String,
     pub description: String,
-    // phrase : room name
-    pub doors: HashMap<String, String>,
-    // characters: ,
-    // contents: ,
+    // phrase : door
+    pub doors: HashMap<String, Door>,
+    // NPC IDs
+    pub characters: Vec<String>,
+    // item IDs
+    pub contents: Vec<String>,
+    // device IDs
+    pub devices: Vec<String>,
+}
+
+/// Brackets the first occurrence of `phrase` in `text` that isn't
+/// already inside brackets, or returns None if there isn't one.
+fn bracket(text: &str, phrase: &str) -> Option<String> {
+    for (start, _) in text.match_indices(phrase) {
+        let before = &text[..start];
+        if before.matches('[').count() > before.matches(']').count() {
+            continue;
+        }
+        let end = start + phrase.len();
+        return Some(format!("{}[{}]{}", before, phrase, &text[end..]));
+    }
+    None
 }
 
 impl Room {
-    pub fn find_door(&self, name: &str) -> Option<&String> {
+    pub fn find_door(&self, name: &str) -> Option<&Door> {
         self.doors.get(name)
     }
 
-    pub fn annotated_description(&self) -> String {
+    pub fn annotated_description(&self, world: &state::World) -> String {
         let mut description = self.description.clone();
-        for door_name in self.doors.keys() {
-            description = description.replace(door_name, &format!("[{}]", door_name));
+        // Longest first, so "service lift" is bracketed whole rather
+        // than around "lift"
+        let mut door_names: Vec<&String> = self.doors.keys().collect();
+        door_names.sort_by(|a, b| b.len().cmp(&a.len()));
+        for door_name in door_names {
+            if let Some(annotated) = bracket(&description, door_name) {
+                description = annotated;
+            }
+        }
+
+        for npc in self.characters.iter().filter_map(|id| world.get_npc(id)) {
+            match bracket(&description, &npc.name) {
+                Some(annotated) => description = annotated,
+                None => description.push_str(&format!(" [{}] is here.", npc.name)),
+            }
+        }
+
+        for device in self.devices.iter().filter_map(|id| world.get_device(id)) {
+            match bracket(&description, &device.name) {
+                Some(annotated) => description = annotated,
+                None => description.push_str(&format!(" There is a [{}] here.", device.name)),
+            }
+        }
+
+        for item in self.contents.iter().filter_map(|id| world.get_item(id)) {
+            match bracket(&description, &item.name) {
+                Some(annotated) => description = annotated,
+                None => description.push_str(&format!(" There is a [{}] here.", item.name)),
+            }
         }
 
         description
@@ -36,11 +95,54 @@ impl assets::Decodable for (String, Room) {
         let exits = get_field!(doc, "exits", as_hash);
 
         let mut doors = HashMap::new();
+        let mut characters = vec![];
+        let mut contents = vec![];
+        let mut devices = vec![];
 
-        for (room_name, exit_names) in exits.iter() {
+        // Exits are either a list of phrases, or a hash with the
+        // phrases and any conditions on using the door
+        for (room_name, exit) in exits.iter() {
             let room_name = as_value!(room_name, as_str).to_owned();
-            for exit_name in as_value!(exit_names, as_vec).iter() {
-                doors.insert(as_value!(exit_name, as_str).to_owned(), room_name.clone());
+            let mut door = Door {
+                room: room_name,
+                check: None,
+                lock: None,
+                locked_message: None,
+            };
+            let exit_names = if exit.as_vec().is_some() {
+                as_value!(exit, as_vec)
+            }
+            else {
+                if !exit["check"].is_badvalue() {
+                    door.check = Some(try!(assets::Decodable::decode(&exit["check"])));
+                }
+                if !exit["lock"].is_badvalue() {
+                    door.lock = Some(try!(assets::Decodable::decode(&exit["lock"])));
+                }
+                door.locked_message = get_optional_field!(exit, "locked_message", as_str).map(|m| m.to_owned());
+                get_field!(exit, "phrases", as_vec)
+            };
+
+            for exit_name in exit_names.iter() {
+                doors.insert(as_value!(exit_name, as_str).to_owned(), door.clone());
+            }
+        }
+
+        if let Some(npcs) = get_optional_field!(doc, "characters", as_vec) {
+            for npc_id in npcs.iter() {
+                characters.push(as_value!(npc_id, as_str).to_owned());
+            }
+        }
+
+        if let Some(items) = get_optional_field!(doc, "contents", as_vec) {
+            for item_id in items.iter() {
+                contents.push(as_value!(item_id, as_str).to_owned());
+            }
+        }
+
+        if let Some(ids) = get_optional_field!(doc, "devices", as_vec) {
+            for device_id in ids.iter() {
+                devices.push(as_value!(device_id, as_str).to_owned());
             }
         }
 
@@ -48,17 +150,13 @@ impl assets::Decodable for (String, Room) {
             name: name.to_owned(),
             description: desc.to_owned(),
             doors: doors,
+            characters: characters,
+            contents: contents,
+            devices: devices,
         }))
     }
 }
 
 pub fn load_rooms(docs: Vec<yaml_rust::Yaml>) -> assets::Result<HashMap<String, Room>> {
-    let mut result = HashMap::with_capacity(docs.len());
-
-    for room in docs.iter().map(assets::Decodable::decode) {
-        let (key, room) = try!(room);
-        result.insert(key, room);
-    }
-
-    Ok(result)
+    assets::load_table(docs)
 }
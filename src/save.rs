@@ -0,0 +1,209 @@
+use std;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+use yaml_rust::{self, Yaml};
+
+use assets;
+
+/// Bump this whenever the save format changes incompatibly.
+pub const SAVE_VERSION: i64 = 1;
+pub const SAVE_DIRECTORY: &'static str = "saves";
+
+#[derive(Debug)]
+pub enum SaveError {
+    InvalidSlot(String),
+    Io(std::io::Error),
+    Emit(yaml_rust::EmitError),
+    Scan(yaml_rust::ScanError),
+    Decode(assets::DecodeError),
+    WrongVersion(i64),
+}
+
+impl std::fmt::Display for SaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
+        match *self {
+            SaveError::InvalidSlot(ref slot) => write!(f, "Invalid save slot name {}", slot),
+            SaveError::Io(ref err) => write!(f, "Could not access save file: {}", err),
+            SaveError::Emit(ref err) => write!(f, "Could not write save file: {:?}", err),
+            SaveError::Scan(ref err) => write!(f, "Save file is corrupted: {}", err),
+            SaveError::Decode(ref err) => write!(f, "Save file is corrupted: {:?}", err),
+            SaveError::WrongVersion(version) =>
+                write!(f, "Save file is version {}, but this game reads version {}", version, SAVE_VERSION),
+        }
+    }
+}
+
+impl From<std::io::Error> for SaveError {
+    fn from(err: std::io::Error) -> SaveError {
+        SaveError::Io(err)
+    }
+}
+
+impl From<yaml_rust::EmitError> for SaveError {
+    fn from(err: yaml_rust::EmitError) -> SaveError {
+        SaveError::Emit(err)
+    }
+}
+
+impl From<yaml_rust::ScanError> for SaveError {
+    fn from(err: yaml_rust::ScanError) -> SaveError {
+        SaveError::Scan(err)
+    }
+}
+
+impl From<assets::DecodeError> for SaveError {
+    fn from(err: assets::DecodeError) -> SaveError {
+        SaveError::Decode(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, SaveError>;
+
+fn slot_path(slot: &str) -> Result<PathBuf> {
+    let valid = !slot.is_empty() && slot.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        return Err(SaveError::InvalidSlot(slot.to_owned()));
+    }
+
+    let mut path = PathBuf::from(SAVE_DIRECTORY);
+    path.push(format!("{}.yaml", slot));
+    Ok(path)
+}
+
+/// Writes the game state to the given slot, tagged with the save
+/// format version.
+pub fn write(slot: &str, state: Yaml) -> Result<()> {
+    let path = try!(slot_path(slot));
+
+    let doc = assets::hash(vec![
+        ("version", Yaml::Integer(SAVE_VERSION)),
+        ("state", state),
+    ]);
+    let mut contents = String::new();
+    {
+        let mut emitter = yaml_rust::YamlEmitter::new(&mut contents);
+        try!(emitter.dump(&doc));
+    }
+
+    try!(fs::create_dir_all(SAVE_DIRECTORY));
+    let mut file = try!(File::create(&path));
+    try!(file.write_all(contents.as_bytes()));
+    Ok(())
+}
+
+/// Reads the game state from the given slot, checking the save format
+/// version.
+pub fn read(slot: &str) -> Result<Yaml> {
+    let path = try!(slot_path(slot));
+
+    let mut contents = String::new();
+    let mut file = try!(File::open(&path));
+    try!(file.read_to_string(&mut contents));
+
+    let mut docs = try!(yaml_rust::YamlLoader::load_from_str(&contents));
+    if docs.is_empty() {
+        return Err(SaveError::Decode(assets::DecodeError::TypeError(format!("Empty save file"))));
+    }
+    let doc = docs.remove(0);
+
+    let version = doc["version"].as_i64().unwrap_or(0);
+    if version != SAVE_VERSION {
+        return Err(SaveError::WrongVersion(version));
+    }
+
+    Ok(doc["state"].clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::rc::Rc;
+
+    use chrono;
+    use chrono::offset::TimeZone;
+
+    use player::Attribute;
+    use state::Game;
+    use ui::transcript::TranscriptFrontend;
+
+    use super::{read, slot_path, SaveError, SAVE_DIRECTORY, SAVE_VERSION};
+
+    fn new_game() -> Game {
+        let frontend = TranscriptFrontend::new(vec![], Rc::new(RefCell::new(vec![])));
+        Game::new(::load_world("rooms.yaml"), 1, Box::new(frontend))
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut game = new_game();
+        game.room = "arrivals_hall".to_owned();
+        game.time = chrono::UTC.ymd(2048, 1, 3).and_hms(22, 15, 30);
+        game.player.name = "Ana".to_owned();
+        game.player.hp.reduce(30);
+        game.player.cpu.reduce(45);
+        game.player.credits = 125;
+        game.player.inventory = vec!["pen".to_owned(), "visa".to_owned()];
+        game.player.set_attribute(Attribute::Intellect, 11);
+        game.world.flags.insert("signed_entry_form".to_owned());
+        game.world.get_room_mut("border_office_1").unwrap().contents.clear();
+        game.world.get_room_mut("arrivals_hall").unwrap().characters.clear();
+        game.world.get_device_mut("lift_controller").unwrap().reprogram(vec![0x0000006f]).unwrap();
+        game.save("test_round_trip").unwrap();
+
+        let mut loaded = new_game();
+        let result = loaded.load("test_round_trip");
+        fs::remove_file(slot_path("test_round_trip").unwrap()).unwrap();
+        result.unwrap();
+
+        assert_eq!(loaded.room, "arrivals_hall");
+        assert_eq!(loaded.time, chrono::UTC.ymd(2048, 1, 3).and_hms(22, 15, 30));
+        assert_eq!(loaded.player.name, "Ana");
+        assert_eq!((loaded.player.hp.current, loaded.player.hp.max), (70, 100));
+        assert_eq!((loaded.player.cpu.current, loaded.player.cpu.max), (55, 100));
+        assert_eq!(loaded.player.credits, 125);
+        assert_eq!(loaded.player.inventory, vec!["pen", "visa"]);
+        assert_eq!(loaded.player.attribute(Attribute::Intellect), 11);
+        assert_eq!(loaded.player.attribute(Attribute::Strength), 5);
+        assert_eq!(loaded.world.flags, game.world.flags);
+        assert!(loaded.world.get_room("border_office_1").unwrap().contents.is_empty());
+        assert!(loaded.world.get_room("arrivals_hall").unwrap().characters.is_empty());
+        assert_eq!(loaded.world.get_room("border_office_3").unwrap().contents, vec!["stim"]);
+        assert_eq!(loaded.world.get_device("lift_controller").unwrap().program, vec![0x0000006f]);
+    }
+
+    #[test]
+    fn wrong_version() {
+        let saves = [
+            ("test_old_version", "version: 0\nstate: {}\n".to_owned()),
+            ("test_new_version", format!("version: {}\nstate: {{}}\n", SAVE_VERSION + 1)),
+            ("test_no_version", "state: {}\n".to_owned()),
+        ];
+        fs::create_dir_all(SAVE_DIRECTORY).unwrap();
+        for &(slot, ref contents) in saves.iter() {
+            let path = slot_path(slot).unwrap();
+            File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+            let result = read(slot);
+            fs::remove_file(&path).unwrap();
+
+            match result {
+                Err(SaveError::WrongVersion(version)) => assert!(version != SAVE_VERSION),
+                Err(err) => panic!("{}: {}", slot, err),
+                Ok(_) => panic!("{}: loaded a save with the wrong version", slot),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_slots() {
+        for slot in &["", "../rooms", "a b", "saves/x"] {
+            match slot_path(slot) {
+                Err(SaveError::InvalidSlot(ref name)) => assert_eq!(name, slot),
+                _ => panic!("accepted slot name {:?}", slot),
+            }
+        }
+    }
+}
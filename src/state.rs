@@ -1,24 +1,45 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ops::Add;
 
 use chrono;
 use chrono::offset::TimeZone;
+use yaml_rust::Yaml;
 
+use assets;
+use combat;
+use creation;
+use device;
+use dialogue;
+use dice;
+use item;
+use lock;
+use npc;
+use player;
 use room;
+use save;
 
-use ui;
-use ui::input::InputHandler;
-use ui::window::{Pad, ScrollingOutput, Window, WindowLike};
+use ui::Frontend;
+use ui::input::InputError;
 
 pub struct World {
     rooms: HashMap<String, room::Room>,
+    items: HashMap<String, item::Item>,
+    npcs: HashMap<String, npc::Npc>,
+    devices: HashMap<String, device::Device>,
+    pub flags: HashSet<String>,
 }
 
 impl World {
-    pub fn new(rooms: HashMap<String, room::Room>) -> World {
+    pub fn new(rooms: HashMap<String, room::Room>,
+               items: HashMap<String, item::Item>,
+               npcs: HashMap<String, npc::Npc>,
+               devices: HashMap<String, device::Device>) -> World {
         World {
             rooms: rooms,
+            items: items,
+            npcs: npcs,
+            devices: devices,
+            flags: HashSet::new(),
         }
     }
 
@@ -29,114 +50,204 @@ impl World {
     pub fn get_room(&self, id: &str) -> Option<&room::Room> {
         self.rooms.get(id)
     }
-}
-
-struct UILayout {
-    border: Window,
-    main: Window,
-    output: RefCell<Pad>,
-    character: Window,
-    title: RefCell<Window>,
-    input: Window,
-}
-
-impl UILayout {
-    fn new() -> UILayout {
-        let (width, height) = ui::term_size();
-        let main_width = 2 * width / 3;
-        let mut border_win = Window::new(0, 3, main_width, height - 6);
-        border_win.box_(0, 0);
-        border_win.refresh();
-        let main_win = Window::new(1, 4, main_width - 2, height - 8);
-        main_win.refresh();
-
-        let output = Pad::new(100, 80);
-
-        let mut char_info = Window::new(main_width, 0, width - main_width, height);
-        char_info.box_(0, 0);
-        char_info.refresh();
-
-        let mut title_win = Window::new(0, 0, main_width, 3);
-        title_win.box_(0, 0);
-        title_win.refresh();
-
-        let mut input_win = Window::new(0, height - 3, main_width, 3);
-        input_win.box_(0, 0);
-        input_win.refresh();
 
-        UILayout {
-            border: border_win,
-            main: main_win,
-            output: RefCell::new(output),
-            character: char_info,
-            title: RefCell::new(title_win),
-            input: input_win,
-        }
+    pub fn get_room_mut(&mut self, id: &str) -> Option<&mut room::Room> {
+        self.rooms.get_mut(id)
     }
 
-    fn refresh(&self) {
-        self.title.borrow_mut().refresh();
-        self.output.borrow_mut().render(&self.main);
+    pub fn get_item(&self, id: &str) -> Option<&item::Item> {
+        self.items.get(id)
     }
 
-    fn set_title(&self, left: &str, right: &str) {
-        let width = (ui::term_size().0 * 2 / 3) as usize
-            - right.len()
-            - 1  // TODO: why?
-            - self.title.borrow_mut().margins().horizontal() as usize;
-        self.title.borrow_mut().print(0, &format!("{:<width$} {}", left, right, width=width));
+    pub fn get_npc(&self, id: &str) -> Option<&npc::Npc> {
+        self.npcs.get(id)
     }
 
-    fn display(&self, text: &str) {
-        self.output.borrow_mut().append_wrap(text);
+    pub fn get_device(&self, id: &str) -> Option<&device::Device> {
+        self.devices.get(id)
     }
-}
-
-impl InputHandler for UILayout {
 
+    pub fn get_device_mut(&mut self, id: &str) -> Option<&mut device::Device> {
+        self.devices.get_mut(id)
+    }
 }
 
 pub struct Game {
-    // player: ,
+    pub player: player::Player,
     pub world: World,
-    ui: UILayout,
+    ui: Box<Frontend>,
     pub room: String,
+    // where the player wakes up after losing a fight
+    previous_room: Option<String>,
     pub time: chrono::DateTime<chrono::UTC>,
+    rng: dice::Rng,
 }
 
 impl Game {
-    pub fn new(world: World) -> Game {
+    pub fn new(world: World, seed: u64, ui: Box<Frontend>) -> Game {
         Game {
-            ui: UILayout::new(),
+            ui: ui,
+            player: player::Player::new("Nobody"),
             world: world,
             room: "NO_ROOM_SET".to_owned(),
+            previous_room: None,
             time: chrono::UTC.ymd(2048, 1, 2).and_hms(7, 7, 0),
+            rng: dice::Rng::new(seed),
+        }
+    }
+
+    pub fn create_character(&mut self) -> Result<(), InputError> {
+        self.player = try!(creation::create_character(&*self.ui));
+        Ok(())
+    }
+
+    /// Loads the given save slot, or creates a new character in the
+    /// starting room if there is no slot or it fails to load. Fails if
+    /// input runs out before the game begins.
+    pub fn start(&mut self, start_room: &str, load_slot: Option<&str>) -> Result<(), InputError> {
+        if let Some(slot) = load_slot {
+            match self.load(slot) {
+                Ok(()) => return Ok(()),
+                Err(err) => self.ui.display(&format!("{}", err)),
+            }
+        }
+
+        try!(self.create_character());
+        if !try!(self.enter_room(start_room)) {
+            panic!("No room {}", start_room);
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, slot: &str) -> save::Result<()> {
+        save::write(slot, self.save_state())
+    }
+
+    pub fn load(&mut self, slot: &str) -> save::Result<()> {
+        let state = try!(save::read(slot));
+        try!(self.restore_state(&state));
+
+        self.describe_room();
+        self.update_title();
+        self.update_character();
+        Ok(())
+    }
+
+    fn save_state(&self) -> Yaml {
+        let rooms = self.world.rooms.iter().map(|(id, room)| {
+            (&id[..], assets::hash(vec![
+                ("characters", assets::strings(&room.characters)),
+                ("contents", assets::strings(&room.contents)),
+            ]))
+        }).collect();
+
+        let mut flags: Vec<&String> = self.world.flags.iter().collect();
+        flags.sort();
+
+        let devices = self.world.devices.iter().map(|(id, device)| {
+            (&id[..], Yaml::Array(device.program.iter().map(|&word| Yaml::Integer(word as i64)).collect()))
+        }).collect();
+
+        assets::hash(vec![
+            ("room", Yaml::String(self.room.clone())),
+            ("time", Yaml::Integer(self.time.timestamp())),
+            ("player", assets::Encodable::encode(&self.player)),
+            ("flags", assets::strings(flags)),
+            ("rooms", assets::hash(rooms)),
+            ("devices", assets::hash(devices)),
+        ])
+    }
+
+    fn restore_state(&mut self, doc: &Yaml) -> assets::Result<()> {
+        // Decode everything before touching the game, so that a bad
+        // save can't leave it half-loaded
+        let room = get_field!(doc, "room", as_str).to_owned();
+        if !self.world.rooms.contains_key(&room) {
+            return Err(assets::DecodeError::TypeError(format!("Unknown room {}", room)));
+        }
+        let time = chrono::UTC.timestamp(get_field!(doc, "time", as_i64), 0);
+        let player = try!(assets::Decodable::decode(&doc["player"]));
+        let flags = try!(assets::decode_strings(&doc["flags"]));
+
+        let mut rooms = vec![];
+        for (id, state) in get_field!(doc, "rooms", as_hash).iter() {
+            let id = as_value!(id, as_str).to_owned();
+            if !self.world.rooms.contains_key(&id) {
+                return Err(assets::DecodeError::TypeError(format!("Unknown room {}", id)));
+            }
+            rooms.push((id,
+                        try!(assets::decode_strings(&state["characters"])),
+                        try!(assets::decode_strings(&state["contents"]))));
+        }
+
+        let mut devices = vec![];
+        for (id, words) in get_field!(doc, "devices", as_hash).iter() {
+            let id = as_value!(id, as_str).to_owned();
+            let mut program = vec![];
+            for word in as_value!(words, as_vec).iter() {
+                program.push(as_value!(word, as_i64) as u32);
+            }
+            match self.world.devices.get(&id) {
+                Some(device) => try!(device.check_program(&program).map_err(assets::DecodeError::TypeError)),
+                None => return Err(assets::DecodeError::TypeError(format!("Unknown device {}", id))),
+            }
+            devices.push((id, program));
+        }
+
+        self.room = room;
+        self.previous_room = None;
+        self.time = time;
+        self.player = player;
+        self.world.flags = flags.into_iter().collect();
+        for (id, characters, contents) in rooms {
+            let room = self.world.get_room_mut(&id).unwrap();
+            room.characters = characters;
+            room.contents = contents;
+        }
+        for (id, program) in devices {
+            try!(self.world.devices.get_mut(&id).unwrap().reprogram(program)
+                 .map_err(assets::DecodeError::TypeError));
         }
+        Ok(())
     }
 
     fn current_room(&self) -> &room::Room {
         self.world.get_room(&self.room).expect(&format!("Invalid room {}", self.room))
     }
 
+    fn current_room_mut(&mut self) -> &mut room::Room {
+        let room = self.room.clone();
+        self.world.get_room_mut(&room).expect(&format!("Invalid room {}", room))
+    }
+
     fn current_time(&self) -> String {
         format!("{}", self.time.format("%a %d %b %Y %H:%M"))
     }
 
-    pub fn enter_room(&mut self, room: &str) -> Option<()> {
+    /// Returns false if there's no such room.
+    pub fn enter_room(&mut self, room: &str) -> Result<bool, InputError> {
+        self.arrive(room, false)
+    }
+
+    fn arrive(&mut self, room: &str, sneaking: bool) -> Result<bool, InputError> {
         if !self.world.rooms.contains_key(room) {
-            None
+            Ok(false)
         }
         else {
             self.time = self.time.add(chrono::Duration::seconds(30));
+            if self.room != "NO_ROOM_SET" {
+                self.previous_room = Some(self.room.clone());
+            }
             self.room = room.into();
             self.describe_room();
             self.update_title();
-            Some(())
+            try!(self.engage_hostiles(sneaking));
+            Ok(true)
         }
     }
 
     fn describe_room(&self) {
-        self.ui.display(&self.current_room().annotated_description());
+        self.ui.display(&self.current_room().annotated_description(&self.world));
     }
 
     fn update_title(&self) {
@@ -144,10 +255,656 @@ impl Game {
         self.ui.set_title(&room.name, &self.current_time());
     }
 
-    pub fn main(&mut self) {
+    fn update_character(&self) {
+        self.ui.show_character(&self.player.status_lines());
+    }
+
+    fn display_status(&self) {
+        for line in self.player.status_lines() {
+            self.ui.display(&line);
+        }
+    }
+
+    fn inventory_weight(&self) -> u32 {
+        self.player.inventory.iter()
+            .filter_map(|id| self.world.get_item(id))
+            .map(|item| item.weight)
+            .sum()
+    }
+
+    fn take(&mut self, name: &str) {
+        let index = item::find_by_name(&self.current_room().contents, &self.world.items, name);
+        match index {
+            Some(index) => {
+                let id = self.current_room().contents[index].clone();
+                let (item_name, weight) = {
+                    let item = self.world.get_item(&id).expect(&format!("Invalid item {}", id));
+                    (item.name.clone(), item.weight)
+                };
+
+                if self.inventory_weight() + weight > self.player.carry_capacity() {
+                    self.ui.display(&format!("The {} is too heavy to carry with everything else.", item_name));
+                    return;
+                }
+
+                self.current_room_mut().contents.remove(index);
+                self.player.inventory.push(id);
+                self.ui.display(&format!("You take the {}.", item_name));
+            }
+            None => self.ui.display(&format!("There is no {} here.", name)),
+        }
+    }
+
+    fn drop(&mut self, name: &str) {
+        match item::find_by_name(&self.player.inventory, &self.world.items, name) {
+            Some(index) => {
+                let id = self.player.inventory.remove(index);
+                let item_name = self.world.get_item(&id).map_or(id.clone(), |item| item.name.clone());
+                self.current_room_mut().contents.push(id);
+                self.ui.display(&format!("You drop the {}.", item_name));
+            }
+            None => self.ui.display(&format!("You aren't carrying a {}.", name)),
+        }
+    }
+
+    fn display_inventory(&self) {
+        if self.player.inventory.is_empty() {
+            self.ui.display("You aren't carrying anything.");
+            return;
+        }
+
+        for item in self.player.inventory.iter().filter_map(|id| self.world.get_item(id)) {
+            self.ui.display(&format!("[{}] ({})", item.name, item.weight));
+        }
+        self.ui.display(&format!("Carrying {}/{}", self.inventory_weight(), self.player.carry_capacity()));
+    }
+
+    fn examine(&self, name: &str) {
+        let room = self.current_room();
+        if let Some(index) = npc::find_by_name(&room.characters, &self.world.npcs, name) {
+            let npc = &self.world.npcs[&room.characters[index]];
+            self.ui.display(&npc.description);
+            return;
+        }
+        if let Some(index) = device::find_by_name(&room.devices, &self.world.devices, name) {
+            let device = &self.world.devices[&room.devices[index]];
+            self.ui.display(&device.description);
+            return;
+        }
+
+        let item = item::find_by_name(&room.contents, &self.world.items, name)
+            .map(|index| &room.contents[index])
+            .or_else(|| item::find_by_name(&self.player.inventory, &self.world.items, name)
+                     .map(|index| &self.player.inventory[index]))
+            .and_then(|id| self.world.get_item(id));
+
+        match item {
+            Some(item) => self.ui.display(&item.description),
+            None => self.ui.display(&format!("You don't see a {} here.", name)),
+        }
+    }
+
+    fn item_name(&self, id: &str) -> String {
+        self.world.get_item(id).map_or(id.to_owned(), |item| item.name.clone())
+    }
+
+    fn apply_effect(&mut self, effect: &dialogue::Effect) {
+        match *effect {
+            dialogue::Effect::SetFlag(ref flag) => {
+                self.world.flags.insert(flag.clone());
+            }
+            dialogue::Effect::ClearFlag(ref flag) => {
+                self.world.flags.remove(flag);
+            }
+            dialogue::Effect::GiveItem(ref id) => {
+                self.player.inventory.push(id.clone());
+                self.ui.display(&format!("You receive the [{}].", self.item_name(id)));
+            }
+            dialogue::Effect::TakeItem(ref id) => {
+                if let Some(index) = self.player.inventory.iter().position(|i| i == id) {
+                    self.player.inventory.remove(index);
+                    self.ui.display(&format!("You hand over the [{}].", self.item_name(id)));
+                }
+            }
+            dialogue::Effect::Credits(amount) => {
+                if amount >= 0 {
+                    self.player.credits += amount as u32;
+                    self.ui.display(&format!("You receive {} credits.", amount));
+                }
+                else {
+                    let amount = ::std::cmp::min(self.player.credits, (-amount) as u32);
+                    self.player.credits -= amount;
+                    self.ui.display(&format!("You pay {} credits.", amount));
+                }
+            }
+        }
+    }
+
+    fn talk(&mut self, name: &str) -> Result<(), InputError> {
+        let npc_id = {
+            let room = self.current_room();
+            match npc::find_by_name(&room.characters, &self.world.npcs, name) {
+                Some(index) => room.characters[index].clone(),
+                None => {
+                    self.ui.display(&format!("There is no {} here.", name));
+                    return Ok(());
+                }
+            }
+        };
+
+        // Temporarily take the NPC out of the world so that dialogue
+        // effects can mutate the game state while we walk the tree
+        let npc = self.world.npcs.remove(&npc_id).unwrap();
+        let result = match npc.dialogue {
+            Some(ref dialogue) => self.converse(&npc.name, dialogue),
+            None => {
+                self.ui.display(&format!("{} has nothing to say to you.", npc.name));
+                Ok(())
+            }
+        };
+        self.world.npcs.insert(npc_id, npc);
+        result
+    }
+
+    fn converse(&mut self, name: &str, dialogue: &dialogue::Dialogue) -> Result<(), InputError> {
+        let mut current = dialogue.start.clone();
+
+        while let Some(node) = dialogue.node(&current) {
+            for line in node.lines.iter() {
+                self.ui.say(name, line);
+            }
+
+            let choices: Vec<&dialogue::Choice> = node.choices.iter()
+                .filter(|choice| choice.is_available(&self.player, &self.world.flags))
+                .collect();
+            if choices.is_empty() {
+                break;
+            }
+
+            for (i, choice) in choices.iter().enumerate() {
+                self.ui.display(&format!("[{}] {}", i + 1, choice.text));
+            }
+
+            let choice = choices[try!(self.ui.prompt_number("choice>", 1, choices.len() as i32)) as usize - 1];
+
+            let passed = match choice.check {
+                Some(ref check) => self.skill_check(check),
+                None => true,
+            };
+            let next = if passed {
+                for effect in choice.effects.iter() {
+                    self.apply_effect(effect);
+                }
+                self.update_character();
+                &choice.next
+            }
+            else {
+                &choice.failure
+            };
+
+            match *next {
+                Some(ref next) => current = next.clone(),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Rolls a skill check and shows the breakdown.
+    fn skill_check(&mut self, check: &dice::SkillCheck) -> bool {
+        let result = check.roll(&self.player, &mut self.rng);
+        self.ui.display(&format!("({})", result));
+        result.success()
+    }
+
+    fn go(&mut self, target: &str, sneaking: bool) -> Result<(), InputError> {
+        let door = self.current_room().find_door(target).cloned();
+        match door {
+            Some(door) => {
+                if !self.is_unlocked(&door) {
+                    let message = door.locked_message.clone()
+                        .unwrap_or(format!("The {} is locked.", target));
+                    self.ui.display(&message);
+                    return Ok(());
+                }
+
+                if let Some(ref check) = door.check {
+                    if !self.skill_check(check) {
+                        self.time = self.time.add(chrono::Duration::seconds(30));
+                        self.ui.display(&format!("You can't make it through the {}.", target));
+                        return Ok(());
+                    }
+                }
+
+                if sneaking {
+                    self.time = self.time.add(chrono::Duration::seconds(30));
+                }
+                if !try!(self.arrive(&door.room, sneaking)) {
+                    panic!("No room {}", door.room);
+                }
+            }
+            None => self.ui.display(&format!("Can't go to {}", target)),
+        }
+        Ok(())
+    }
+
+    fn unlock_flag(&self, door: &room::Door) -> String {
+        format!("unlocked_{}_{}", self.room, door.room)
+    }
+
+    fn is_unlocked(&self, door: &room::Door) -> bool {
+        match door.lock {
+            None => true,
+            Some(lock::Lock::Flag(ref flag)) => self.world.flags.contains(flag),
+            Some(_) => self.world.flags.contains(&self.unlock_flag(door)),
+        }
+    }
+
+    fn unlock_door(&mut self, door: &room::Door, name: &str) {
+        let flag = self.unlock_flag(door);
+        self.world.flags.insert(flag);
+        self.ui.display(&format!("The {} unlocks.", name));
+    }
+
+    /// Finds a door in the current room that is still locked, telling
+    /// the player if there isn't one.
+    fn find_locked_door(&self, name: &str) -> Option<room::Door> {
+        match self.current_room().find_door(name) {
+            Some(door) if !self.is_unlocked(door) => Some(door.clone()),
+            Some(_) => {
+                self.ui.display(&format!("The {} isn't locked.", name));
+                None
+            }
+            None => {
+                self.ui.display(&format!("There is no {} here.", name));
+                None
+            }
+        }
+    }
+
+    fn unlock(&mut self, name: &str) -> Result<(), InputError> {
+        let door = match self.find_locked_door(name) {
+            Some(door) => door,
+            None => return Ok(()),
+        };
+
+        match door.lock {
+            Some(lock::Lock::Code(ref code)) => {
+                let input = try!(self.ui.prompt("code>"));
+                self.time = self.time.add(chrono::Duration::seconds(5));
+                if input.trim() == code {
+                    self.unlock_door(&door, name);
+                }
+                else {
+                    self.ui.display("The keypad buzzes angrily.");
+                }
+            }
+            Some(lock::Lock::Item(ref id)) => {
+                if self.player.inventory.contains(id) {
+                    self.ui.display(&format!("You use the [{}].", self.item_name(id)));
+                    self.unlock_door(&door, name);
+                }
+                else {
+                    self.ui.display(&format!("You need a [{}].", self.item_name(id)));
+                }
+            }
+            _ => self.ui.display(&format!("The {} won't budge.", name)),
+        }
+        Ok(())
+    }
+
+    /// Uses up a consumable item from the inventory. Returns false if
+    /// the item isn't consumable.
+    fn use_consumable(&mut self, id: &str) -> bool {
+        let heal = match self.world.get_item(id) {
+            Some(item) if item.has_tag("consumable") => item.heal,
+            _ => return false,
+        };
+
+        if let Some(index) = self.player.inventory.iter().position(|i| i == id) {
+            self.player.inventory.remove(index);
+        }
+        self.player.hp.restore(heal);
+        self.ui.display(&format!("You use the [{}] and recover {} HP.", self.item_name(id), heal));
+        true
+    }
+
+    /// Handles `use <item>` and `use <item> on <door>`.
+    fn use_item(&mut self, args: &[&str]) {
+        let (item_name, target) = match args.iter().position(|&arg| arg == "on") {
+            Some(index) => (args[..index].join(" "), Some(args[index + 1..].join(" "))),
+            None => (args.join(" "), None),
+        };
+
+        let id = match item::find_by_name(&self.player.inventory, &self.world.items, &item_name) {
+            Some(index) => self.player.inventory[index].clone(),
+            None => {
+                self.ui.display(&format!("You aren't carrying a {}.", item_name));
+                return;
+            }
+        };
+        let (is_brute_force, rate) = {
+            let item = self.world.get_item(&id).unwrap();
+            (item.has_tag("brute-force"), item.rate)
+        };
+
+        let target = match target {
+            Some(target) => target,
+            None => {
+                if !self.use_consumable(&id) {
+                    self.ui.display(&format!("Use the [{}] on what?", self.item_name(&id)));
+                }
+                return;
+            }
+        };
+
+        let door = match self.find_locked_door(&target) {
+            Some(door) => door,
+            None => return,
+        };
+        match door.lock {
+            Some(lock::Lock::Item(ref key)) if *key == id => self.unlock_door(&door, &target),
+            Some(lock::Lock::Code(ref code)) if is_brute_force => self.brute_force(&door, &target, &id, code, rate),
+            _ => self.ui.display("Nothing happens."),
+        }
+    }
+
+    /// Simulates a key trying every code in order until it finds the
+    /// right one, advancing the clock as it goes.
+    fn brute_force(&mut self, door: &room::Door, name: &str, key: &str, code: &str, rate: u32) {
+        let attempts = match lock::brute_force_attempts(code) {
+            Some(attempts) => attempts,
+            None => {
+                self.ui.display(&format!("The [{}] can't make sense of this lock.", self.item_name(key)));
+                return;
+            }
+        };
+        let rate = ::std::cmp::max(1, rate as u64);
+
+        self.ui.display(&format!("You hook the [{}] up to the {} keypad. It starts cycling through {}-digit codes.",
+                                 self.item_name(key), name, code.len()));
+        // Report progress every quarter of the way to the code
+        let step = ::std::cmp::max(1, attempts / 4);
+        let mut tried = step;
+        while tried < attempts {
+            self.ui.display(&format!("{:0width$}... {:0width$}...", tried - 1, tried, width = code.len()));
+            tried += step;
+        }
+
+        let seconds = ::std::cmp::max(1, attempts / rate);
+        self.time = self.time.add(chrono::Duration::seconds(seconds as i64));
+        self.ui.display(&format!("{}. Click. ({} codes in {} seconds)", code, attempts, seconds));
+        self.unlock_door(door, name);
+    }
+
+    fn find_device(&self, name: &str) -> Option<String> {
+        let room = self.current_room();
+        match device::find_by_name(&room.devices, &self.world.devices, name) {
+            Some(index) => Some(room.devices[index].clone()),
+            None => {
+                self.ui.display(&format!("There is no {} here.", name));
+                None
+            }
+        }
+    }
+
+    fn read_device(&self, name: &str) {
+        if let Some(id) = self.find_device(name) {
+            for line in self.world.devices[&id].listing() {
+                self.ui.display(&line);
+            }
+        }
+    }
+
+    /// Prompts for a new program, one hexadecimal machine word per
+    /// line, ending with a blank line.
+    fn reprogram_device(&mut self, name: &str) -> Result<(), InputError> {
+        let id = match self.find_device(name) {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+
+        self.ui.display("Enter machine words in hex, then a blank line to finish.");
+        let mut words = vec![];
+        loop {
+            let line = try!(self.ui.prompt(&format!("{:04x}>", 4 * words.len())));
+            let line = line.trim();
+            if line.is_empty() {
+                break;
+            }
+            words.extend(line.split_whitespace().map(|word| word.to_owned()));
+        }
+
+        match device::parse_program(&words) {
+            Ok(program) => {
+                let device = self.world.devices.get_mut(&id).unwrap();
+                match device.reprogram(program) {
+                    Ok(()) => {
+                        self.time = self.time.add(chrono::Duration::minutes(1));
+                        self.ui.display(&format!("You flash the [{}].", device.name));
+                    }
+                    Err(err) => self.ui.display(&err),
+                }
+            }
+            Err(err) => self.ui.display(&err),
+        }
+        Ok(())
+    }
+
+    fn run_devices(&mut self) {
+        for device in self.world.devices.values_mut() {
+            device.run(&self.player, &mut self.world.flags, &self.time);
+        }
+    }
+
+    fn engage_hostiles(&mut self, sneaking: bool) -> Result<(), InputError> {
+        let enemies: Vec<combat::Enemy> = self.current_room().characters.iter()
+            .filter_map(|id| self.world.get_npc(id).map(|npc| (id, npc)))
+            .filter(|&(_, npc)| npc.hostile)
+            .filter_map(|(id, npc)| npc.combat.as_ref().map(|stats| combat::Enemy::new(id, &npc.name, stats)))
+            .collect();
+
+        if enemies.is_empty() {
+            return Ok(());
+        }
+
+        let combat = combat::Combat::new(enemies);
+        if sneaking {
+            let check = dice::SkillCheck::new(player::Attribute::Reflexes, combat.stealth_difficulty());
+            if self.skill_check(&check) {
+                self.ui.display("You slip past unnoticed.");
+                return Ok(());
+            }
+            self.ui.display("You've been spotted!");
+        }
+        self.fight(combat)
+    }
+
+    fn weapon_damage(&self) -> i32 {
+        self.player.inventory.iter()
+            .filter_map(|id| self.world.get_item(id))
+            .filter(|item| item.has_tag("weapon"))
+            .map(|item| item.damage)
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn fight(&mut self, mut combat: combat::Combat) -> Result<(), InputError> {
+        for enemy in combat.enemies.iter() {
+            self.ui.display(&format!("[{}] attacks!", enemy.name));
+        }
+
+        let rolls = combat.roll_initiative(&self.player, &mut self.rng);
+        let rolls: Vec<String> = rolls.iter().map(|&(combatant, roll)| match combatant {
+            combat::Combatant::Player => format!("you {}", roll),
+            combat::Combatant::Enemy(index) => format!("{} {}", combat.enemies[index].name, roll),
+        }).collect();
+        self.ui.display(&format!("(Initiative: {})", rolls.join(", ")));
+
+        let outcome = try!(self.run_combat(&mut combat));
+        match outcome {
+            combat::Outcome::Victory => self.win_combat(&combat),
+            combat::Outcome::Defeat => self.lose_combat(),
+        }
+        self.update_title();
+        self.update_character();
+        Ok(())
+    }
+
+    fn run_combat(&mut self, combat: &mut combat::Combat) -> Result<combat::Outcome, InputError> {
+        loop {
+            combat.round += 1;
+            self.time = self.time.add(chrono::Duration::seconds(combat::ROUND_SECONDS));
+            self.update_title();
+            self.ui.display(&format!("-- Round {} --", combat.round));
+
+            for turn in combat.order.clone() {
+                if let Some(outcome) = combat.outcome(&self.player) {
+                    return Ok(outcome);
+                }
+
+                match turn {
+                    combat::Combatant::Player => try!(self.player_turn(combat)),
+                    combat::Combatant::Enemy(index) => {
+                        if !combat.enemies[index].is_alive() {
+                            continue;
+                        }
+                        let message = match combat.enemy_turn(index, &mut self.player) {
+                            combat::EnemyAction::Attack(damage) =>
+                                format!("[{}] hits you for {} damage.", combat.enemies[index].name, damage),
+                            combat::EnemyAction::Defend =>
+                                format!("[{}] braces for your next move.", combat.enemies[index].name),
+                        };
+                        self.ui.display(&message);
+                    }
+                }
+                self.update_character();
+            }
+
+            if let Some(outcome) = combat.outcome(&self.player) {
+                return Ok(outcome);
+            }
+        }
+    }
+
+    fn player_turn(&mut self, combat: &mut combat::Combat) -> Result<(), InputError> {
+        combat.player_defending = false;
+
+        loop {
+            let input = try!(self.ui.prompt("combat>"));
+            let parts: Vec<&str> = input.split_whitespace().collect();
+            let (command, args) = match parts.split_first() {
+                Some((command, args)) => (*command, args.join(" ")),
+                None => continue,
+            };
+
+            match command {
+                "attack" | "hack" => {
+                    let target = match combat.find_target(&args) {
+                        Some(target) => target,
+                        None => {
+                            self.ui.display(&format!("{} whom?", command));
+                            continue;
+                        }
+                    };
+                    let name = combat.enemies[target].name.clone();
+
+                    if command == "attack" {
+                        let damage = combat.player_attack(&self.player, target, self.weapon_damage());
+                        self.ui.display(&format!("You hit [{}] for {} damage.", name, damage));
+                    }
+                    else {
+                        match combat.player_hack(&mut self.player, target) {
+                            Some(damage) => self.ui.display(
+                                &format!("You fry [{}]'s implants for {} damage.", name, damage)),
+                            None => {
+                                self.ui.display("Not enough CPU.");
+                                continue;
+                            }
+                        }
+                    }
+
+                    if !combat.enemies[target].is_alive() {
+                        self.ui.display(&format!("[{}] goes down.", name));
+                    }
+                    return Ok(());
+                }
+                "defend" => {
+                    combat.player_defending = true;
+                    self.ui.display("You brace yourself.");
+                    return Ok(());
+                }
+                "use" => {
+                    let id = item::find_by_name(&self.player.inventory, &self.world.items, &args)
+                        .map(|index| self.player.inventory[index].clone());
+                    match id {
+                        Some(ref id) if self.use_consumable(id) => return Ok(()),
+                        _ => self.ui.display(&format!("You can't use {} here.", args)),
+                    }
+                }
+                "status" => {
+                    self.display_status();
+                    for enemy in combat.enemies.iter().filter(|enemy| enemy.is_alive()) {
+                        self.ui.display(&format!("[{}] HP {}/{}", enemy.name, enemy.hp.current, enemy.hp.max));
+                    }
+                }
+                _ => self.ui.display("You can [attack], [hack], [defend] or [use] an item."),
+            }
+            self.ui.refresh();
+        }
+    }
+
+    fn win_combat(&mut self, combat: &combat::Combat) {
+        self.ui.display("You win the fight.");
+
+        for enemy in combat.enemies.iter() {
+            if let Some(index) = self.current_room().characters.iter().position(|id| *id == enemy.id) {
+                self.current_room_mut().characters.remove(index);
+            }
+            self.world.flags.insert(format!("defeated_{}", enemy.id));
+
+            let (credits, loot) = match self.world.get_npc(&enemy.id).and_then(|npc| npc.combat.as_ref()) {
+                Some(stats) => (stats.credits, stats.loot.clone()),
+                None => (0, vec![]),
+            };
+            if credits > 0 {
+                self.player.credits += credits;
+                self.ui.display(&format!("You find {} credits on [{}].", credits, enemy.name));
+            }
+            for id in loot {
+                self.ui.display(&format!("[{}] drops a [{}].", enemy.name, self.item_name(&id)));
+                self.current_room_mut().contents.push(id);
+            }
+        }
+    }
+
+    fn lose_combat(&mut self) {
+        self.ui.display("Everything goes dark.");
+
+        self.time = self.time.add(chrono::Duration::hours(8));
+        let lost = self.player.credits / 2;
+        self.player.credits -= lost;
+        self.player.hp.current = ::std::cmp::max(1, self.player.hp.max / 4);
+
+        // Without a previous room, e.g. when the game starts in a hostile
+        // room, wake up at the start instead of straight back in the fight
+        self.room = self.previous_room.take().unwrap_or_else(|| ::START_ROOM.to_owned());
+        self.ui.display(&format!("You wake up hours later, {} credits lighter.", lost));
+        self.describe_room();
+    }
+
+    /// Runs commands until the player exits or input runs out at the
+    /// command prompt. Fails if input runs out in the middle of a
+    /// command, e.g. at a dialogue choice, or can't be read.
+    pub fn main(&mut self) -> Result<(), InputError> {
+        self.update_character();
         self.ui.refresh();
         loop {
-            let input = self.ui.get_line(&self.ui.input).unwrap();
+            let input = match self.ui.read_line() {
+                Ok(input) => input,
+                Err(ref err) if err.is_end_of_input() => break,
+                Err(err) => return Err(err),
+            };
             let parts: Vec<&str> = input.split_whitespace().collect();
             let parts: Option<(&&str, &[&str])> = parts.split_first();
             if parts.is_none() {
@@ -158,19 +915,56 @@ impl Game {
             match *command {
                 "exit" => break,
                 "describe" => self.describe_room(),
-                "go" => {
+                "status" => self.display_status(),
+                "inventory" => self.display_inventory(),
+                "talk" => {
+                    // Accept both "talk clerk" and "talk to clerk"
+                    let args = if args.first() == Some(&"to") { &args[1..] } else { args };
+                    if args.is_empty() {
+                        self.ui.display("Talk to whom?");
+                    }
+                    else {
+                        try!(self.talk(&args.join(" ")));
+                    }
+                },
+                "save" | "load" if args.is_empty() => {
+                    self.ui.display(&format!("{} which slot?", command));
+                },
+                "save" => {
+                    let slot = args.join("_");
+                    match self.save(&slot) {
+                        Ok(()) => self.ui.display(&format!("Saved to slot {}.", slot)),
+                        Err(err) => self.ui.display(&format!("{}", err)),
+                    }
+                },
+                "load" => {
+                    let slot = args.join("_");
+                    if let Err(err) = self.load(&slot) {
+                        self.ui.display(&format!("{}", err));
+                    }
+                },
+                "unlock" | "use" if args.is_empty() => {
+                    self.ui.display(&format!("{} what?", command));
+                },
+                "unlock" => try!(self.unlock(&args.join(" "))),
+                "use" => self.use_item(args),
+                "read" | "reprogram" if args.is_empty() => {
+                    self.ui.display(&format!("{} what?", command));
+                },
+                "read" => self.read_device(&args.join(" ")),
+                "reprogram" => try!(self.reprogram_device(&args.join(" "))),
+                "take" | "drop" | "examine" if args.is_empty() => {
+                    self.ui.display(&format!("{} what?", command));
+                },
+                "take" => self.take(&args.join(" ")),
+                "drop" => self.drop(&args.join(" ")),
+                "examine" => self.examine(&args.join(" ")),
+                "go" | "sneak" => {
                     if args.is_empty() {
                         self.ui.display("Go where?");
                     }
                     else {
-                        let target = args.join(" ");
-                        let result = { self.current_room().find_door(&target).map(|x| x.to_owned()) };
-                        if let Some(room_name) = result {
-                            self.enter_room(&room_name).unwrap();
-                        }
-                        else {
-                            self.ui.display(&format!("Can't go to {}", target));
-                        }
+                        try!(self.go(&args.join(" "), *command == "sneak"));
                     }
                 },
                 _ => {
@@ -178,8 +972,11 @@ impl Game {
                 },
             }
 
+            self.run_devices();
             self.update_title();
+            self.update_character();
             self.ui.refresh();
         }
+        Ok(())
     }
 }
@@ -0,0 +1,230 @@
+//! Transcripts replay a scene from a file and check what the game
+//! printed and where the player ended up. A transcript looks like:
+//!
+//! ```text
+//! # Comments start with a hash
+//! ! rooms rooms.yaml
+//! ! start border_office_1
+//! ! seed 42
+//! Florescent lights glare
+//! > take pamphlet
+//! You take the pamphlet.
+//! ? has pamphlet
+//! ? room border_office_1
+//! ```
+//!
+//! `>` lines are fed to the game as input, including the answers to
+//! character creation. Plain lines are expected output: each must
+//! appear, in order, somewhere in what the game printed after the
+//! preceding input (a substring of a line is enough). `?` lines are
+//! checked against the final state once the inputs run out. The
+//! inputs must run out at the command prompt; a transcript that ends in
+//! the middle of character creation, a conversation or a fight fails.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::Read;
+use std::rc::Rc;
+
+use state::{Game, World};
+use ui::transcript::{Recording, TranscriptFrontend};
+
+const DEFAULT_SEED: u64 = 1;
+
+pub enum Expectation {
+    Room(String),
+    Flag(String),
+    NotFlag(String),
+    // item ID in the player's inventory
+    Has(String),
+    Lacks(String),
+    Credits(u32),
+    Hp(i32),
+}
+
+impl Expectation {
+    fn parse(text: &str) -> Result<Expectation, String> {
+        let mut parts = text.split_whitespace();
+        let kind = parts.next().unwrap_or("");
+        let value = match parts.next() {
+            Some(value) => value.to_owned(),
+            None => return Err(format!("Expectation {} needs a value", kind)),
+        };
+        let number = || value.parse().map_err(|_| format!("{} is not a number", value));
+
+        Ok(match kind {
+            "room" => Expectation::Room(value.clone()),
+            "flag" => Expectation::Flag(value.clone()),
+            "not_flag" => Expectation::NotFlag(value.clone()),
+            "has" => Expectation::Has(value.clone()),
+            "lacks" => Expectation::Lacks(value.clone()),
+            "credits" => Expectation::Credits(try!(number()) as u32),
+            "hp" => Expectation::Hp(try!(number())),
+            _ => return Err(format!("Unknown expectation {}", kind)),
+        })
+    }
+
+    /// Returns a description of the mismatch, if there is one.
+    fn check(&self, game: &Game) -> Option<String> {
+        match *self {
+            Expectation::Room(ref room) if game.room != *room =>
+                Some(format!("expected to be in {}, but in {}", room, game.room)),
+            Expectation::Flag(ref flag) if !game.world.flags.contains(flag) =>
+                Some(format!("expected flag {} to be set", flag)),
+            Expectation::NotFlag(ref flag) if game.world.flags.contains(flag) =>
+                Some(format!("expected flag {} to be clear", flag)),
+            Expectation::Has(ref id) if !game.player.inventory.contains(id) =>
+                Some(format!("expected to carry {}", id)),
+            Expectation::Lacks(ref id) if game.player.inventory.contains(id) =>
+                Some(format!("expected not to carry {}", id)),
+            Expectation::Credits(credits) if game.player.credits != credits =>
+                Some(format!("expected {} credits, but have {}", credits, game.player.credits)),
+            Expectation::Hp(hp) if game.player.hp.current != hp =>
+                Some(format!("expected {} HP, but have {}", hp, game.player.hp.current)),
+            _ => None,
+        }
+    }
+}
+
+pub struct Transcript {
+    pub path: String,
+    pub rooms: Option<String>,
+    pub start: Option<String>,
+    pub seed: u64,
+    // (line number, text)
+    inputs: Vec<(usize, String)>,
+    lines: usize,
+    // (line number, text), grouped by the input they follow; entry 0
+    // is for output before the first input
+    output: Vec<Vec<(usize, String)>>,
+    expectations: Vec<(usize, Expectation)>,
+}
+
+impl Transcript {
+    pub fn load(path: &str) -> Result<Transcript, String> {
+        let mut contents = String::new();
+        try!(File::open(path)
+             .and_then(|mut file| file.read_to_string(&mut contents))
+             .map_err(|err| format!("{}: {}", path, err)));
+
+        let mut transcript = Transcript {
+            path: path.to_owned(),
+            rooms: None,
+            start: None,
+            seed: DEFAULT_SEED,
+            inputs: vec![],
+            lines: 0,
+            output: vec![vec![]],
+            expectations: vec![],
+        };
+
+        for (i, line) in contents.lines().enumerate() {
+            let number = i + 1;
+            transcript.lines = number;
+            let error = |message: String| format!("{}:{}: {}", path, number, message);
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with("#") {
+                continue;
+            }
+            else if line.starts_with(">") {
+                transcript.inputs.push((number, line[1..].trim().to_owned()));
+                transcript.output.push(vec![]);
+            }
+            else if line.starts_with("?") {
+                let expectation = try!(Expectation::parse(&line[1..]).map_err(&error));
+                transcript.expectations.push((number, expectation));
+            }
+            else if line.starts_with("!") {
+                let mut parts = line[1..].split_whitespace();
+                match (parts.next(), parts.next()) {
+                    (Some("rooms"), Some(rooms)) => transcript.rooms = Some(rooms.to_owned()),
+                    (Some("start"), Some(start)) => transcript.start = Some(start.to_owned()),
+                    (Some("seed"), Some(seed)) => {
+                        transcript.seed = try!(seed.parse().map_err(|_| error(format!("{} is not a seed", seed))));
+                    }
+                    _ => return Err(error(format!("Unknown directive {}", line))),
+                }
+            }
+            else {
+                transcript.output.last_mut().unwrap().push((number, line.to_owned()));
+            }
+        }
+
+        Ok(transcript)
+    }
+
+    /// Plays the transcript in a new game and returns every mismatch
+    /// found, so an empty list means it passed.
+    pub fn run(&self, world: World, start_room: &str) -> Vec<String> {
+        let recording: Recording = Rc::new(RefCell::new(vec![]));
+        let inputs = self.inputs.iter().map(|&(_, ref input)| input.clone()).collect();
+        let frontend = TranscriptFrontend::new(inputs, recording.clone());
+        let mut game = Game::new(world, self.seed, Box::new(frontend));
+        let result = game.start(start_room, None).and_then(|()| game.main());
+
+        let recording = recording.borrow();
+        let mut failures = vec![];
+        match result {
+            Err(ref err) if err.is_end_of_input() => {
+                let line = self.inputs.last().map_or(self.lines, |&(number, _)| number);
+                failures.push(format!("{}: transcript ended early at line {}", self.path, line));
+            }
+            Err(err) => failures.push(format!("{}: {}", self.path, err)),
+            Ok(()) => (),
+        }
+        for (step, expected) in self.output.iter().enumerate() {
+            let actual = recording.get(step).map_or(&[][..], |lines| &lines[..]);
+            let mut remaining = actual.iter();
+            for &(number, ref text) in expected.iter() {
+                if !remaining.any(|line| line.contains(&text[..])) {
+                    let after = if step == 0 {
+                        "at the start".to_owned()
+                    }
+                    else {
+                        format!("after > {}", self.inputs[step - 1].1)
+                    };
+                    let mut failure = format!("{}:{}: expected \"{}\" {}, got:", self.path, number, text, after);
+                    for line in actual.iter() {
+                        failure.push_str("\n    ");
+                        failure.push_str(line);
+                    }
+                    failures.push(failure);
+                    break;
+                }
+            }
+        }
+
+        for &(number, ref expectation) in self.expectations.iter() {
+            if let Some(message) = expectation.check(&game) {
+                failures.push(format!("{}:{}: {}", self.path, number, message));
+            }
+        }
+
+        failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::Transcript;
+
+    #[test]
+    fn transcripts_pass() {
+        let mut failures = vec![];
+        for entry in fs::read_dir("transcripts").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().map_or(true, |extension| extension != "txt") {
+                continue;
+            }
+
+            let transcript = Transcript::load(path.to_str().unwrap()).unwrap();
+            let world = ::load_world(transcript.rooms.as_ref().map_or("rooms.yaml", |rooms| &rooms[..]));
+            let start = transcript.start.as_ref().map_or(::START_ROOM, |start| &start[..]);
+            failures.extend(transcript.run(world, start));
+        }
+        assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+    }
+}
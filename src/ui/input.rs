@@ -8,6 +8,8 @@ use ui::window::{Window, WindowLike};
 #[derive(Debug)]
 pub enum InputErrorReason {
     DecodeError(std::str::Utf8Error),
+    IoError(std::io::Error),
+    EndOfInput,
 }
 
 #[derive(Debug)]
@@ -16,6 +18,21 @@ pub struct InputError {
     // buffer so far for resumption
 }
 
+impl InputError {
+    pub fn end_of_input() -> InputError {
+        InputError {
+            reason: InputErrorReason::EndOfInput,
+        }
+    }
+
+    pub fn is_end_of_input(&self) -> bool {
+        match self.reason {
+            InputErrorReason::EndOfInput => true,
+            _ => false,
+        }
+    }
+}
+
 impl std::fmt::Display for InputError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
         write!(f, "InputError({:?})", self.reason)
@@ -26,6 +43,8 @@ impl std::error::Error for InputError {
     fn description(&self) -> &str {
         match self.reason {
             InputErrorReason::DecodeError(ref err) => err.description(),
+            InputErrorReason::IoError(ref err) => err.description(),
+            InputErrorReason::EndOfInput => "end of input",
         }
     }
 }
@@ -38,6 +57,14 @@ impl From<std::str::Utf8Error> for InputError {
     }
 }
 
+impl From<std::io::Error> for InputError {
+    fn from(err: std::io::Error) -> InputError {
+        InputError {
+            reason: InputErrorReason::IoError(err),
+        }
+    }
+}
+
 pub trait InputHandler {
     // Input functions can return an error if they are interrupted by
     // one of the handle* functions. TODO: add way to resume input
@@ -0,0 +1,104 @@
+use std::cell::RefCell;
+
+use ncurses;
+
+use ui;
+use ui::input::{InputError, InputHandler};
+use ui::window::{Pad, ScrollingOutput, Window, WindowLike};
+
+/// The ncurses frontend: a title bar, a scrolling output pad, an input
+/// line and a character sheet down the right side.
+pub struct UILayout {
+    border: Window,
+    main: Window,
+    output: RefCell<Pad>,
+    character: RefCell<Window>,
+    title: RefCell<Window>,
+    input: Window,
+}
+
+impl UILayout {
+    pub fn new() -> UILayout {
+        let locale_conf = ncurses::LcCategory::all;
+        ncurses::setlocale(locale_conf, "en_US.UTF-8");
+        ncurses::initscr();
+        ncurses::raw();
+        ncurses::nonl();
+        ncurses::keypad(ncurses::stdscr, true);
+        ncurses::noecho();
+        ncurses::curs_set(ncurses::CURSOR_VISIBILITY::CURSOR_INVISIBLE);
+
+        ncurses::refresh();
+
+        let (width, height) = ui::term_size();
+        let main_width = 2 * width / 3;
+        let mut border_win = Window::new(0, 3, main_width, height - 6);
+        border_win.box_(0, 0);
+        border_win.refresh();
+        let main_win = Window::new(1, 4, main_width - 2, height - 8);
+        main_win.refresh();
+
+        let output = Pad::new(100, 80);
+
+        let mut char_info = Window::new(main_width, 0, width - main_width, height);
+        char_info.box_(0, 0);
+        char_info.refresh();
+
+        let mut title_win = Window::new(0, 0, main_width, 3);
+        title_win.box_(0, 0);
+        title_win.refresh();
+
+        let mut input_win = Window::new(0, height - 3, main_width, 3);
+        input_win.box_(0, 0);
+        input_win.refresh();
+
+        UILayout {
+            border: border_win,
+            main: main_win,
+            output: RefCell::new(output),
+            character: RefCell::new(char_info),
+            title: RefCell::new(title_win),
+            input: input_win,
+        }
+    }
+}
+
+impl Drop for UILayout {
+    fn drop(&mut self) {
+        ncurses::endwin();
+    }
+}
+
+impl InputHandler for UILayout {
+
+}
+
+impl ui::Frontend for UILayout {
+    fn refresh(&self) {
+        self.title.borrow_mut().refresh();
+        self.output.borrow_mut().render(&self.main);
+    }
+
+    fn set_title(&self, left: &str, right: &str) {
+        let width = (ui::term_size().0 * 2 / 3) as usize
+            - right.len()
+            - 1  // TODO: why?
+            - self.title.borrow_mut().margins().horizontal() as usize;
+        self.title.borrow_mut().print(0, &format!("{:<width$} {}", left, right, width=width));
+    }
+
+    fn display(&self, text: &str) {
+        self.output.borrow_mut().append_wrap(text);
+    }
+
+    fn show_character(&self, lines: &[String]) {
+        let mut character = self.character.borrow_mut();
+        for (row, line) in lines.iter().enumerate() {
+            character.print(row as i32, line);
+        }
+    }
+
+    fn read_line(&self) -> Result<String, InputError> {
+        self.get_line(&self.input)
+    }
+}
@@ -1,8 +1,43 @@
 use ncurses;
 
 pub mod input;
+pub mod layout;
+pub mod stdio;
+pub mod transcript;
 pub mod window;
 
+/// Everything the game needs from a user interface.
+pub trait Frontend {
+    fn refresh(&self);
+    fn set_title(&self, left: &str, right: &str);
+    fn display(&self, text: &str);
+    fn show_character(&self, lines: &[String]);
+    fn read_line(&self) -> Result<String, input::InputError>;
+
+    fn say(&self, name: &str, text: &str) {
+        self.display(&format!("{}: {}", name, text));
+    }
+
+    /// Shows the prompt and reads the player's answer. Fails at the
+    /// end of input, which should end the game.
+    fn prompt(&self, text: &str) -> Result<String, input::InputError> {
+        self.display(text);
+        self.refresh();
+        self.read_line()
+    }
+
+    /// Prompts until the player enters a number between `min` and
+    /// `max`, inclusive.
+    fn prompt_number(&self, text: &str, min: i32, max: i32) -> Result<i32, input::InputError> {
+        loop {
+            match try!(self.prompt(text)).trim().parse::<i32>() {
+                Ok(n) if n >= min && n <= max => return Ok(n),
+                _ => self.display(&format!("Enter a number from {} to {}.", min, max)),
+            }
+        }
+    }
+}
+
 pub fn term_size() -> (i32, i32) {
     let mut x = 0;
     let mut y = 0;
@@ -0,0 +1,56 @@
+use std;
+use std::cell::RefCell;
+use std::io::{BufRead, Write};
+
+use ui;
+use ui::input::InputError;
+
+/// A plain line-based frontend on stdin/stdout, for pipes and terminals
+/// without curses.
+pub struct StdioFrontend {
+    // only print the title when it changes
+    title: RefCell<String>,
+}
+
+impl StdioFrontend {
+    pub fn new() -> StdioFrontend {
+        StdioFrontend {
+            title: RefCell::new(String::new()),
+        }
+    }
+}
+
+impl ui::Frontend for StdioFrontend {
+    fn refresh(&self) {
+        std::io::stdout().flush().unwrap();
+    }
+
+    fn set_title(&self, left: &str, right: &str) {
+        let title = format!("== {} | {} ==", left, right);
+        if *self.title.borrow() != title {
+            println!("{}", title);
+            *self.title.borrow_mut() = title;
+        }
+    }
+
+    fn display(&self, text: &str) {
+        println!("{}", text);
+    }
+
+    fn show_character(&self, _lines: &[String]) {
+        // There's no sidebar; the status command shows the same thing
+    }
+
+    fn read_line(&self) -> Result<String, InputError> {
+        print!("> ");
+        self.refresh();
+
+        let mut line = String::new();
+        let stdin = std::io::stdin();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => Err(InputError::end_of_input()),
+            Ok(_) => Ok(line.trim_right_matches(|c| c == '\n' || c == '\r').to_owned()),
+            Err(err) => Err(InputError::from(err)),
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use ui;
+use ui::input::InputError;
+
+/// Everything the game printed, split up by input: entry 0 is the
+/// output before the first line was read, entry 1 the output after
+/// it, and so on.
+pub type Recording = Rc<RefCell<Vec<Vec<String>>>>;
+
+/// A frontend that plays back a fixed list of inputs and records the
+/// output, for running transcripts.
+pub struct TranscriptFrontend {
+    inputs: RefCell<VecDeque<String>>,
+    recording: Recording,
+    title: RefCell<String>,
+}
+
+impl TranscriptFrontend {
+    pub fn new(inputs: Vec<String>, recording: Recording) -> TranscriptFrontend {
+        recording.borrow_mut().push(vec![]);
+        TranscriptFrontend {
+            inputs: RefCell::new(inputs.into_iter().collect()),
+            recording: recording,
+            title: RefCell::new(String::new()),
+        }
+    }
+
+    fn record(&self, line: String) {
+        self.recording.borrow_mut().last_mut().unwrap().push(line);
+    }
+}
+
+impl ui::Frontend for TranscriptFrontend {
+    fn refresh(&self) {}
+
+    fn set_title(&self, left: &str, right: &str) {
+        // Same format as the stdio frontend, so transcripts can be
+        // written by copying a plain session
+        let title = format!("== {} | {} ==", left, right);
+        if *self.title.borrow() != title {
+            self.record(title.clone());
+            *self.title.borrow_mut() = title;
+        }
+    }
+
+    fn display(&self, text: &str) {
+        for line in text.lines() {
+            self.record(line.to_owned());
+        }
+    }
+
+    fn show_character(&self, _lines: &[String]) {}
+
+    fn read_line(&self) -> Result<String, InputError> {
+        match self.inputs.borrow_mut().pop_front() {
+            Some(line) => {
+                self.recording.borrow_mut().push(vec![]);
+                Ok(line)
+            }
+            None => Err(InputError::end_of_input()),
+        }
+    }
+}